@@ -0,0 +1,126 @@
+//! Compares `Deferred` against `ArrayDeferred` in time and number of allocations.
+//!
+//! Run with `cargo bench`.
+
+use ::deferred::*;
+use std::alloc::{GlobalAlloc, Layout, System};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::Instant;
+
+struct CountingAllocator;
+
+static ALLOCATIONS: AtomicUsize = AtomicUsize::new(0);
+
+unsafe impl GlobalAlloc for CountingAllocator {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        ALLOCATIONS.fetch_add(1, Ordering::Relaxed);
+        System.alloc(layout)
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        System.dealloc(ptr, layout)
+    }
+}
+
+#[global_allocator]
+static ALLOCATOR: CountingAllocator = CountingAllocator;
+
+const ITERATIONS: usize = 1_000_000;
+
+fn heap(v: u64) -> Deferred<u64> {
+    deferred!(
+        v,
+        [
+            |c| state!(c.state() + 1),
+            |c| state!(c.state() * 2),
+            |c| state!(c.state() + 3),
+            |c| state!(c.state() * 4)
+        ]
+    )
+}
+
+fn array(v: u64) -> ArrayDeferred<u64, 4> {
+    ArrayDeferred::new(
+        v,
+        &[
+            |c| state!(c.state() + 1),
+            |c| state!(c.state() * 2),
+            |c| state!(c.state() + 3),
+            |c| state!(c.state() * 4),
+        ],
+    )
+}
+
+fn heap_nested(v: u64) -> Deferred<u64> {
+    deferred!(
+        v,
+        [
+            |c| state!(c.state() + 1),
+            |c| deferred!(
+                c.state(),
+                [|c| state!(c.state() * 2), |c| state!(c.state() + 3)]
+            )
+            .into(),
+            |c| state!(c.state() * 4)
+        ]
+    )
+}
+
+struct Inner;
+
+impl StaticSubroutine<u64> for Inner {
+    const PARTS: &'static [Part<u64>] = &[|c| state!(c.state() * 2), |c| state!(c.state() + 3)];
+}
+
+fn array_nested(v: u64) -> ArrayDeferred<u64, 4> {
+    ArrayDeferred::new(
+        v,
+        &[
+            |c| state!(c.state() + 1),
+            |c| Context::from_static::<Inner>(c.state()),
+            |c| state!(c.state() * 4),
+        ],
+    )
+}
+
+fn bench<F>(name: &str, mut f: F)
+where
+    F: FnMut(u64) -> u64,
+{
+    let allocations = ALLOCATIONS.load(Ordering::Relaxed);
+    let timer = Instant::now();
+    let mut result = 0u64;
+    for i in 0..ITERATIONS {
+        result = f(i as u64).wrapping_add(result);
+    }
+    let elapsed = timer.elapsed();
+    let allocations = ALLOCATIONS.load(Ordering::Relaxed) - allocations;
+    println!(
+        "{:<24} {:>8.2} ns/iter {:>6.2} allocs/iter (result: {})",
+        name,
+        elapsed.as_nanos() as f64 / ITERATIONS as f64,
+        allocations as f64 / ITERATIONS as f64,
+        result
+    );
+}
+
+fn main() {
+    bench("Deferred::consume", |v| heap(v).consume());
+    bench("ArrayDeferred::consume", |v| array(v).consume());
+    bench("Deferred::nested", |v| heap_nested(v).consume());
+    bench("ArrayDeferred::nested", |v| array_nested(v).consume());
+    bench("Deferred::resume", |v| {
+        let mut d = heap(v);
+        while d.can_resume() {
+            d = d.resume().unwrap();
+        }
+        *d.state().unwrap()
+    });
+    bench("ArrayDeferred::resume", |v| {
+        let mut d = array(v);
+        while d.can_resume() {
+            d = d.resume().unwrap();
+        }
+        *d.state().unwrap()
+    });
+}
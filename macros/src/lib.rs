@@ -0,0 +1,713 @@
+//! Procedural macros for `deferred` crate.
+//!
+//! # Linear functions with yield points
+//! Instead of writing each step of deferred execution as separate part that threads state by
+//! hand, you can write normal function body and mark points where execution should be suspended
+//! with `yield_now!();` (or `yield_now().await;` if you prefer it). Function gets turned into
+//! state machine with generated enum holding local variables declared so far, one variant per
+//! yield point, so locals stay typed and nothing gets boxed between steps.
+//! ```
+//! use ::deferred::linear::deferred;
+//! use ::deferred::*;
+//!
+//! #[deferred]
+//! fn foo(v: i32) -> String {
+//!     let mut total = v + 1;
+//!     yield_now!();
+//!     total *= 2;
+//!     yield_now().await;
+//!     format!("total: {}", total)
+//! }
+//!
+//! let d = foo(1);
+//! let d = d.resume().unwrap();
+//! let d = d.resume().unwrap();
+//! assert!(d.can_resume());
+//! assert_eq!(d.consume().into_result(), Some("total: 4".to_owned()));
+//! ```
+//!
+//! Yield points can be placed also in bodies of `loop` and `while` loops, which is handy for
+//! splitting work into chunks:
+//! ```
+//! use ::deferred::linear::deferred;
+//! use ::deferred::*;
+//!
+//! #[deferred]
+//! fn sum(items: Vec<u32>) -> u32 {
+//!     let mut total = 0;
+//!     let mut items = items.into_iter();
+//!     while let Some(item) = items.next() {
+//!         total += item;
+//!         yield_now!();
+//!     }
+//!     total
+//! }
+//!
+//! let mut d = sum(vec![1, 2, 3]);
+//! let mut steps = 0;
+//! while d.can_resume() {
+//!     d = d.resume().unwrap();
+//!     steps += 1;
+//! }
+//! assert_eq!(steps, 4);
+//! assert_eq!(d.consume().into_result(), Some(6));
+//! ```
+//!
+//! # Limitations
+//! * yield points can be placed only directly in function body or in bodies of `loop` and
+//!   `while` loops placed there (nested loops too), not in other blocks, `if` or `match` arms and
+//!   `for` loops (use `while let` with iterator instead).
+//! * every variable declared before yield point is moved to the next step, so it has to be
+//!   initialized and cannot be used after it was moved somewhere else.
+//! * loop with yield point in its body can be left with `break` only without value.
+//! * `return` and `?` are supported, but not inside other macro invocations.
+
+extern crate proc_macro;
+
+use proc_macro::TokenStream;
+use proc_macro2::{Span, TokenStream as TokenStream2};
+use proc_macro_crate::{crate_name, FoundCrate};
+use quote::{format_ident, quote};
+use syn::{
+    parse_macro_input, parse_quote, spanned::Spanned, visit::Visit, visit_mut::VisitMut, Attribute,
+    Error, Expr, ExprAwait, FnArg, Ident, Item, ItemFn, Label, Lifetime, Macro, Pat, PatIdent,
+    ReturnType, Stmt, Type,
+};
+
+/// Turns function with yield points into function that returns `Deferred<Linear<L, R>>`, where
+/// `L` is generated enum of local variables and `R` is return type of original function.
+#[proc_macro_attribute]
+pub fn deferred(attr: TokenStream, item: TokenStream) -> TokenStream {
+    if !attr.is_empty() {
+        return Error::new(
+            Span::call_site(),
+            "`#[deferred]` attribute does not take arguments",
+        )
+        .to_compile_error()
+        .into();
+    }
+    let item = parse_macro_input!(item as ItemFn);
+    match expand(item) {
+        Ok(stream) => stream.into(),
+        Err(error) => error.to_compile_error().into(),
+    }
+}
+
+/// Local variable carried between steps - `id` is unique per declaration, so shadowing variable
+/// gives new one.
+#[derive(Clone)]
+struct Binding {
+    ident: Ident,
+    mutable: bool,
+    id: usize,
+}
+
+/// Collects identifiers bound by pattern.
+#[derive(Default)]
+struct PatIdents(Vec<(Ident, bool)>);
+
+impl<'ast> Visit<'ast> for PatIdents {
+    fn visit_pat_ident(&mut self, pat: &'ast PatIdent) {
+        self.0.push((pat.ident.clone(), pat.mutability.is_some()));
+        syn::visit::visit_pat_ident(self, pat);
+    }
+}
+
+/// Finds yield points anywhere in visited code.
+#[derive(Default)]
+struct NestedYields(Option<Span>);
+
+impl<'ast> Visit<'ast> for NestedYields {
+    fn visit_macro(&mut self, mac: &'ast Macro) {
+        if is_yield_macro(mac) && self.0.is_none() {
+            self.0 = Some(mac.span());
+        }
+        syn::visit::visit_macro(self, mac);
+    }
+
+    fn visit_expr_await(&mut self, expr: &'ast ExprAwait) {
+        if is_yield_expr(&expr.base) && self.0.is_none() {
+            self.0 = Some(expr.span());
+        }
+        syn::visit::visit_expr_await(self, expr);
+    }
+}
+
+fn is_yield_macro(mac: &Macro) -> bool {
+    mac.path
+        .segments
+        .last()
+        .map(|segment| segment.ident == "yield_now")
+        .unwrap_or(false)
+}
+
+fn is_yield_expr(expr: &Expr) -> bool {
+    match expr {
+        Expr::Macro(expr) => is_yield_macro(&expr.mac),
+        Expr::Call(expr) => match &*expr.func {
+            Expr::Path(path) => path
+                .path
+                .segments
+                .last()
+                .map(|segment| segment.ident == "yield_now")
+                .unwrap_or(false),
+            _ => false,
+        },
+        Expr::Await(expr) => is_yield_expr(&expr.base),
+        _ => false,
+    }
+}
+
+fn is_yield_point(stmt: &Stmt) -> bool {
+    match stmt {
+        Stmt::Macro(stmt) => is_yield_macro(&stmt.mac),
+        Stmt::Expr(expr, _) => is_yield_expr(expr),
+        _ => false,
+    }
+}
+
+fn find_yield<F>(visit: F) -> Option<Span>
+where
+    F: FnOnce(&mut NestedYields),
+{
+    let mut nested = NestedYields::default();
+    visit(&mut nested);
+    nested.0
+}
+
+/// Rewrites `return` and `?` so they complete function from inside of step closure.
+struct Returns<'a> {
+    krate: &'a TokenStream2,
+}
+
+impl VisitMut for Returns<'_> {
+    fn visit_expr_mut(&mut self, expr: &mut Expr) {
+        let krate = self.krate;
+        match expr {
+            Expr::Closure(_) | Expr::Async(_) | Expr::Const(_) => return,
+            Expr::Return(ret) => {
+                let value = match ret.expr.take() {
+                    Some(mut value) => {
+                        self.visit_expr_mut(&mut value);
+                        quote! { #value }
+                    }
+                    None => quote! { () },
+                };
+                ret.expr = Some(parse_quote! { #krate::linear::Resume::Done(#value) });
+                return;
+            }
+            Expr::Try(try_expr) => {
+                self.visit_expr_mut(&mut try_expr.expr);
+                let inner = &try_expr.expr;
+                let value = Ident::new("__value", Span::mixed_site());
+                let residual = Ident::new("__residual", Span::mixed_site());
+                *expr = parse_quote! {
+                    match #krate::linear::Branch::branch(#inner) {
+                        ::core::result::Result::Ok(#value) => #value,
+                        ::core::result::Result::Err(#residual) => {
+                            return #krate::linear::Resume::Done(
+                                #krate::linear::FromResidual::from_residual(#residual),
+                            )
+                        }
+                    }
+                };
+                return;
+            }
+            _ => {}
+        }
+        syn::visit_mut::visit_expr_mut(self, expr);
+    }
+
+    fn visit_item_mut(&mut self, _: &mut Item) {}
+}
+
+/// Loop that execution resumes in the middle of body of, so rest of body is emitted outside of
+/// it: `continue` jumps past labeled block `rest` into the loop and `break` past block `after`.
+struct Virtual {
+    label: Option<Lifetime>,
+    rest: Lifetime,
+    after: Lifetime,
+}
+
+/// Rewrites `break` and `continue` that target loops resumed in the middle of body.
+struct Jumps<'a> {
+    virtuals: &'a [Virtual],
+    /// Labels of loops entered inside rewritten code.
+    real: Vec<Option<Lifetime>>,
+    error: Option<Error>,
+}
+
+impl Jumps<'_> {
+    fn target(&self, label: &Option<Lifetime>) -> Option<&Virtual> {
+        match label {
+            None if self.real.is_empty() => self.virtuals.last(),
+            None => None,
+            Some(label) if self.real.iter().any(|real| real.as_ref() == Some(label)) => None,
+            Some(label) => self
+                .virtuals
+                .iter()
+                .rev()
+                .find(|virt| virt.label.as_ref() == Some(label)),
+        }
+    }
+
+    fn visit_loop<F>(&mut self, label: &Option<Label>, visit: F)
+    where
+        F: FnOnce(&mut Self),
+    {
+        self.real
+            .push(label.as_ref().map(|label| label.name.clone()));
+        visit(self);
+        self.real.pop();
+    }
+}
+
+impl VisitMut for Jumps<'_> {
+    fn visit_expr_mut(&mut self, expr: &mut Expr) {
+        match expr {
+            Expr::Closure(_) | Expr::Async(_) | Expr::Const(_) => {}
+            Expr::Loop(inner) => {
+                let label = inner.label.clone();
+                self.visit_loop(&label, |this| this.visit_block_mut(&mut inner.body));
+            }
+            Expr::While(inner) => {
+                self.visit_expr_mut(&mut inner.cond);
+                let label = inner.label.clone();
+                self.visit_loop(&label, |this| this.visit_block_mut(&mut inner.body));
+            }
+            Expr::ForLoop(inner) => {
+                self.visit_expr_mut(&mut inner.expr);
+                let label = inner.label.clone();
+                self.visit_loop(&label, |this| this.visit_block_mut(&mut inner.body));
+            }
+            Expr::Continue(inner) => {
+                if let Some(virt) = self.target(&inner.label) {
+                    let rest = &virt.rest;
+                    *expr = parse_quote! { break #rest };
+                }
+            }
+            Expr::Break(inner) => match self.target(&inner.label) {
+                Some(_) if inner.expr.is_some() => {
+                    self.error.get_or_insert(Error::new(
+                        inner.span(),
+                        "loop with yield point cannot be left with `break` with value",
+                    ));
+                }
+                Some(virt) => {
+                    let after = &virt.after;
+                    *expr = parse_quote! { break #after };
+                }
+                None => syn::visit_mut::visit_expr_break_mut(self, inner),
+            },
+            _ => syn::visit_mut::visit_expr_mut(self, expr),
+        }
+    }
+
+    fn visit_item_mut(&mut self, _: &mut Item) {}
+}
+
+/// Loop with yield points in its body.
+struct Loop {
+    attrs: Vec<Attribute>,
+    label: Option<Label>,
+    /// Condition of `while` loop.
+    cond: Option<Expr>,
+    body: Vec<Node>,
+}
+
+/// Function body statement split at yield points.
+enum Node {
+    Stmt(Stmt),
+    /// Yield point with index of point execution resumes at.
+    Yield(usize),
+    Loop(Loop),
+}
+
+impl Node {
+    /// Finds indices of nodes leading to yield point, starting at function body.
+    fn path(nodes: &[Node], point: usize) -> Option<Vec<usize>> {
+        nodes
+            .iter()
+            .enumerate()
+            .find_map(|(index, node)| match node {
+                Node::Yield(found) if *found == point => Some(vec![index]),
+                Node::Loop(inner) => Self::path(&inner.body, point).map(|mut path| {
+                    path.insert(0, index);
+                    path
+                }),
+                _ => None,
+            })
+    }
+}
+
+/// Splits function body into nodes and collects variables that are in scope at every point
+/// execution can resume at (first one is function start).
+struct Splitter<'a> {
+    krate: &'a TokenStream2,
+    next_id: usize,
+    points: Vec<Vec<Binding>>,
+}
+
+impl Splitter<'_> {
+    fn declare(&mut self, scope: &mut Vec<Binding>, pat: &Pat) {
+        let mut idents = PatIdents::default();
+        idents.visit_pat(pat);
+        for (ident, mutable) in idents.0 {
+            scope.retain(|binding| binding.ident != ident);
+            scope.push(Binding {
+                ident,
+                mutable,
+                id: self.next_id,
+            });
+            self.next_id += 1;
+        }
+    }
+
+    fn split(&mut self, stmts: Vec<Stmt>, scope: &mut Vec<Binding>) -> syn::Result<Vec<Node>> {
+        let mut nodes = vec![];
+        for stmt in stmts {
+            if is_yield_point(&stmt) {
+                self.points.push(scope.clone());
+                nodes.push(Node::Yield(self.points.len() - 1));
+                continue;
+            }
+            let mut stmt = match stmt {
+                Stmt::Expr(expr, semi) => match self.split_loop(expr, scope)? {
+                    Ok(node) => {
+                        nodes.push(node);
+                        continue;
+                    }
+                    Err(expr) => Stmt::Expr(expr, semi),
+                },
+                stmt => stmt,
+            };
+            if let Some(span) = find_yield(|nested| nested.visit_stmt(&stmt)) {
+                return Err(Error::new(
+                    span,
+                    "yield point can be placed only in function body or `loop` and `while` body",
+                ));
+            }
+            if let Stmt::Local(local) = &stmt {
+                self.declare(scope, &local.pat);
+            }
+            Returns { krate: self.krate }.visit_stmt_mut(&mut stmt);
+            nodes.push(Node::Stmt(stmt));
+        }
+        Ok(nodes)
+    }
+
+    /// Splits loop with yield points in its body, or gives back other expressions.
+    fn split_loop(&mut self, expr: Expr, scope: &[Binding]) -> syn::Result<Result<Node, Expr>> {
+        match expr {
+            Expr::ForLoop(inner) => match find_yield(|nested| nested.visit_block(&inner.body)) {
+                Some(span) => Err(Error::new(
+                    span,
+                    "yield point cannot be placed in `for` loop, iterate with `while let` instead",
+                )),
+                None => Ok(Err(Expr::ForLoop(inner))),
+            },
+            Expr::Loop(inner) if find_yield(|nested| nested.visit_block(&inner.body)).is_some() => {
+                let mut scope = scope.to_vec();
+                Ok(Ok(Node::Loop(Loop {
+                    attrs: inner.attrs,
+                    label: inner.label,
+                    cond: None,
+                    body: self.split(inner.body.stmts, &mut scope)?,
+                })))
+            }
+            Expr::While(inner)
+                if find_yield(|nested| nested.visit_block(&inner.body)).is_some() =>
+            {
+                if let Some(span) = find_yield(|nested| nested.visit_expr(&inner.cond)) {
+                    return Err(Error::new(
+                        span,
+                        "yield point cannot be placed in loop condition",
+                    ));
+                }
+                let mut cond = *inner.cond;
+                let mut scope = scope.to_vec();
+                if let Expr::Let(expr) = &cond {
+                    self.declare(&mut scope, &expr.pat);
+                }
+                Returns { krate: self.krate }.visit_expr_mut(&mut cond);
+                Ok(Ok(Node::Loop(Loop {
+                    attrs: inner.attrs,
+                    label: inner.label,
+                    cond: Some(cond),
+                    body: self.split(inner.body.stmts, &mut scope)?,
+                })))
+            }
+            expr => Ok(Err(expr)),
+        }
+    }
+}
+
+/// Emits code of step closure arms from split function body.
+struct Emitter<'a> {
+    krate: &'a TokenStream2,
+    locals: &'a Ident,
+    points: &'a [Vec<Binding>],
+    nodes: &'a [Node],
+}
+
+impl Emitter<'_> {
+    fn variant(point: usize) -> Ident {
+        format_ident!("P{}", point)
+    }
+
+    fn emit(
+        &self,
+        nodes: &[Node],
+        virtuals: &[Virtual],
+        real: &mut Vec<Option<Lifetime>>,
+    ) -> syn::Result<TokenStream2> {
+        let mut result = quote! {};
+        for node in nodes {
+            match node {
+                Node::Stmt(stmt) if virtuals.is_empty() => result.extend(quote! { #stmt }),
+                Node::Stmt(stmt) => {
+                    let mut stmt = stmt.clone();
+                    let mut jumps = Jumps {
+                        virtuals,
+                        real: real.clone(),
+                        error: None,
+                    };
+                    jumps.visit_stmt_mut(&mut stmt);
+                    if let Some(error) = jumps.error {
+                        return Err(error);
+                    }
+                    result.extend(quote! { #stmt });
+                }
+                Node::Yield(point) => {
+                    let krate = self.krate;
+                    let locals = self.locals;
+                    let variant = Self::variant(*point);
+                    let values = self.points[*point].iter().map(|binding| &binding.ident);
+                    result.extend(quote! {
+                        return #krate::linear::Resume::Yield(#locals::#variant(#(#values,)*));
+                    });
+                }
+                Node::Loop(inner) => result.extend(self.emit_loop(inner, virtuals, real)?),
+            }
+        }
+        Ok(result)
+    }
+
+    fn emit_loop(
+        &self,
+        inner: &Loop,
+        virtuals: &[Virtual],
+        real: &mut Vec<Option<Lifetime>>,
+    ) -> syn::Result<TokenStream2> {
+        let Loop {
+            attrs,
+            label,
+            cond,
+            body,
+        } = inner;
+        real.push(label.as_ref().map(|label| label.name.clone()));
+        let body = self.emit(body, virtuals, real);
+        real.pop();
+        let body = body?;
+        let head = match cond {
+            Some(cond) => quote! { while #cond },
+            None => quote! { loop },
+        };
+        Ok(quote! { #(#attrs)* #label #head { #body } })
+    }
+
+    /// Emits code that continues function from given point until next yield point or its end.
+    fn emit_from(&self, point: usize) -> syn::Result<TokenStream2> {
+        if point == 0 {
+            return self.emit(self.nodes, &[], &mut vec![]);
+        }
+        let path = Node::path(self.nodes, point).expect("Yield point not found");
+        let mut blocks = vec![self.nodes];
+        let mut loops = vec![];
+        for index in &path[..path.len() - 1] {
+            if let Node::Loop(inner) = &blocks[blocks.len() - 1][*index] {
+                blocks.push(&inner.body);
+                loops.push(inner);
+            }
+        }
+        let virtuals = loops
+            .iter()
+            .enumerate()
+            .map(|(level, inner)| Virtual {
+                label: inner.label.as_ref().map(|label| label.name.clone()),
+                rest: Lifetime::new(&format!("'__deferred_rest_{}", level), Span::mixed_site()),
+                after: Lifetime::new(&format!("'__deferred_after_{}", level), Span::mixed_site()),
+            })
+            .collect::<Vec<_>>();
+        let depth = loops.len();
+        let mut result = self.emit(&blocks[depth][path[depth] + 1..], &virtuals, &mut vec![])?;
+        for level in (0..depth).rev() {
+            let Virtual { rest, after, .. } = &virtuals[level];
+            let inner = self.emit_loop(loops[level], &virtuals[..level], &mut vec![])?;
+            let following = self.emit(
+                &blocks[level][path[level] + 1..],
+                &virtuals[..level],
+                &mut vec![],
+            )?;
+            result = quote! {
+                #after: {
+                    #rest: {
+                        #result
+                    }
+                    #inner
+                }
+                #following
+            };
+        }
+        Ok(result)
+    }
+}
+
+fn expand(item: ItemFn) -> syn::Result<TokenStream2> {
+    let ItemFn {
+        attrs,
+        vis,
+        mut sig,
+        block,
+    } = item;
+    if let Some(asyncness) = sig.asyncness {
+        return Err(Error::new(
+            asyncness.span(),
+            "`#[deferred]` cannot be used on async functions",
+        ));
+    }
+    let krate = crate_path();
+    let mut splitter = Splitter {
+        krate: &krate,
+        next_id: 0,
+        points: vec![],
+    };
+    let mut scope = vec![];
+    for input in &sig.inputs {
+        match input {
+            FnArg::Receiver(receiver) => {
+                return Err(Error::new(
+                    receiver.span(),
+                    "`#[deferred]` cannot be used on methods taking `self`",
+                ))
+            }
+            FnArg::Typed(arg) => splitter.declare(&mut scope, &arg.pat),
+        }
+    }
+    splitter.points.push(scope.clone());
+
+    let mut stmts = block.stmts;
+    let tail = match stmts.pop() {
+        Some(Stmt::Expr(expr, None)) if find_yield(|nested| nested.visit_expr(&expr)).is_none() => {
+            Some(expr)
+        }
+        Some(Stmt::Macro(stmt)) if stmt.semi_token.is_none() && !is_yield_macro(&stmt.mac) => {
+            Some(Expr::Macro(syn::ExprMacro {
+                attrs: stmt.attrs,
+                mac: stmt.mac,
+            }))
+        }
+        Some(stmt) => {
+            stmts.push(stmt);
+            None
+        }
+        None => None,
+    };
+    let nodes = splitter.split(stmts, &mut scope)?;
+    let unit = match &sig.output {
+        ReturnType::Default => true,
+        ReturnType::Type(_, ty) => matches!(&**ty, Type::Tuple(tuple) if tuple.elems.is_empty()),
+    };
+    let tail = match tail {
+        Some(mut expr) => {
+            Returns { krate: &krate }.visit_expr_mut(&mut expr);
+            quote! { #krate::linear::Resume::Done(#expr) }
+        }
+        None if unit => quote! { #krate::linear::Resume::Done(()) },
+        None => quote! {},
+    };
+
+    let result = match &sig.output {
+        ReturnType::Default => quote! { () },
+        ReturnType::Type(_, ty) => quote! { #ty },
+    };
+    sig.output = syn::parse2(quote! {
+        -> #krate::Deferred<#krate::Linear<impl Sized, #result>>
+    })?;
+
+    let locals = Ident::new("__DeferredLocals", Span::call_site());
+    let points = splitter.points;
+    let emitter = Emitter {
+        krate: &krate,
+        locals: &locals,
+        points: &points,
+        nodes: &nodes,
+    };
+    let mut params = points
+        .iter()
+        .flatten()
+        .map(|binding| binding.id)
+        .collect::<Vec<_>>();
+    params.sort_unstable();
+    params.dedup();
+    let params = params.into_iter().map(|id| format_ident!("T{}", id));
+    let mut variants = vec![];
+    let mut arms = vec![];
+    for (point, bindings) in points.iter().enumerate() {
+        let variant = Emitter::variant(point);
+        let types = bindings
+            .iter()
+            .map(|binding| format_ident!("T{}", binding.id));
+        variants.push(quote! { #variant(#(#types,)*) });
+        let patterns = bindings.iter().map(|binding| {
+            let ident = &binding.ident;
+            if binding.mutable {
+                quote! { mut #ident }
+            } else {
+                quote! { #ident }
+            }
+        });
+        let code = emitter.emit_from(point)?;
+        arms.push(quote! {
+            #locals::#variant(#(#patterns,)*) => {
+                #code
+                #tail
+            }
+        });
+    }
+    let initial = points[0].iter().map(|binding| &binding.ident);
+    let context = Ident::new("context", Span::mixed_site());
+    let state = Ident::new("locals", Span::mixed_site());
+    Ok(quote! {
+        #(#attrs)*
+        #[allow(unused_mut, unused_variables, unused_assignments, unused_labels, unreachable_code)]
+        #vis #sig {
+            #[allow(non_camel_case_types)]
+            enum #locals<#(#params),*> {
+                #(#variants,)*
+            }
+
+            #krate::Deferred::new(
+                #krate::Linear::new(#locals::P0(#(#initial,)*)),
+                #krate::__private::vec![|#context| #krate::linear::advance(#context, |#state| {
+                    match #state {
+                        #(#arms)*
+                    }
+                })],
+            )
+        }
+    })
+}
+
+/// Gets path of `deferred` crate as it is named by crate that uses macro, so it works also when
+/// dependency is renamed.
+fn crate_path() -> TokenStream2 {
+    match crate_name("deferred") {
+        Ok(FoundCrate::Itself) => quote! { crate },
+        Ok(FoundCrate::Name(name)) => {
+            let name = Ident::new(&name, Span::call_site());
+            quote! { ::#name }
+        }
+        Err(_) => quote! { ::deferred },
+    }
+}
@@ -0,0 +1,152 @@
+use ::deferred::linear::deferred;
+use ::deferred::*;
+
+#[deferred]
+fn countdown(from: u32) -> Vec<u32> {
+    let mut result = vec![from];
+    yield_now!();
+    result.push(from - 1);
+    let (a, b) = (from - 2, from - 3);
+    yield_now().await;
+    result.push(a);
+    yield_now!();
+    result.push(b);
+    result
+}
+
+#[deferred]
+fn no_yields(v: i32) -> i32 {
+    v * 2
+}
+
+#[deferred]
+fn unit(mut v: i32) {
+    v += 1;
+    yield_now!();
+    assert_eq!(v, 2);
+}
+
+#[deferred]
+fn shadowing(v: i32) -> String {
+    let v = v + 1;
+    yield_now!();
+    let v = format!("{}", v);
+    yield_now!();
+    v + "!"
+}
+
+#[deferred]
+fn chunks(items: Vec<u32>, size: usize) -> Vec<u32> {
+    let mut sums = vec![];
+    let mut items = items.into_iter();
+    'outer: loop {
+        let mut sum = 0;
+        let mut count = 0;
+        while let Some(item) = items.next() {
+            if item == 0 {
+                continue;
+            }
+            sum += item;
+            count += 1;
+            yield_now!();
+            if item == 99 {
+                break 'outer;
+            }
+            if count == size {
+                break;
+            }
+        }
+        if count == 0 {
+            break;
+        }
+        sums.push(sum);
+        yield_now!();
+    }
+    sums
+}
+
+#[deferred]
+fn parse(items: Vec<&'static str>) -> Result<i32, std::num::ParseIntError> {
+    let mut total = 0;
+    let mut index = 0;
+    while index < items.len() {
+        let value = items[index].parse::<i32>()?;
+        index += 1;
+        yield_now!();
+        if value < 0 {
+            return Ok(-1);
+        }
+        total += value;
+    }
+    Ok(total)
+}
+
+#[deferred]
+fn generic<T: Clone + std::fmt::Debug>(value: T, times: usize) -> String {
+    let mut result = vec![];
+    while result.len() < times {
+        result.push(value.clone());
+        yield_now!();
+    }
+    format!("{:?}", result)
+}
+
+fn run<L, R>(mut d: Deferred<Linear<L, R>>) -> (usize, R) {
+    let mut steps = 0;
+    while d.can_resume() {
+        d = d.resume().unwrap();
+        steps += 1;
+    }
+    (steps, d.consume().into_result().unwrap())
+}
+
+#[test]
+fn test_steps() {
+    let d = countdown(3);
+    assert!(d.state().unwrap().locals().is_some());
+    let d = d.resume().unwrap();
+    assert!(!d.state().unwrap().is_done());
+    let d = d.resume().unwrap();
+    let d = d.resume().unwrap();
+    assert!(d.can_resume());
+    let d = d.resume().unwrap();
+    assert!(!d.can_resume());
+    assert_eq!(d.state().unwrap().result(), Some(&vec![3, 2, 1, 0]));
+}
+
+#[test]
+fn test_consume() {
+    assert_eq!(
+        countdown(10).consume().into_result(),
+        Some(vec![10, 9, 8, 7])
+    );
+    assert_eq!(no_yields(21).consume().into_result(), Some(42));
+    assert_eq!(unit(1).consume().into_result(), Some(()));
+    assert_eq!(shadowing(1).consume().into_result(), Some("2!".to_owned()));
+}
+
+#[test]
+fn test_manager() {
+    let mut manager = DeferredManager::new();
+    let id = manager.run(countdown(5));
+    manager.resume_all();
+    manager.resume_all();
+    assert!(manager.has(id));
+    let result = manager.consume(id).unwrap().into_result();
+    assert_eq!(result, Some(vec![5, 4, 3, 2]));
+}
+
+#[test]
+fn test_loops() {
+    assert_eq!(run(chunks(vec![1, 2, 0, 3, 4, 5], 2)), (9, vec![3, 7, 5]));
+    assert_eq!(run(chunks(vec![1, 99, 3], 2)), (3, vec![]));
+    assert_eq!(run(chunks(vec![], 2)), (1, vec![]));
+    assert_eq!(run(generic(7u8, 3)), (4, "[7, 7, 7]".to_owned()));
+}
+
+#[test]
+fn test_early_return() {
+    assert_eq!(run(parse(vec!["1", "2", "3"])), (4, Ok(6)));
+    assert_eq!(run(parse(vec!["1", "-2", "3"])), (3, Ok(-1)));
+    assert!(run(parse(vec!["1", "x"])).1.is_err());
+}
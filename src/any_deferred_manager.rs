@@ -0,0 +1,282 @@
+use crate::deferred::*;
+use crate::deferred_manager::Id;
+use crate::value::Value;
+use alloc::{boxed::Box, collections::BTreeMap, vec::Vec};
+use core::{any::Any, marker::PhantomData};
+
+/// Typed handle of deferred execution unit registered in `AnyDeferredManager`.
+///
+/// Handle remembers state type of registered unit so it can be used to get its state back.
+pub struct Handle<S> {
+    id: Id,
+    _state: PhantomData<fn() -> S>,
+}
+
+impl<S> Handle<S> {
+    /// Gets deferred execution id of this handle.
+    #[inline]
+    pub fn id(&self) -> Id {
+        self.id
+    }
+}
+
+impl<S> Clone for Handle<S> {
+    fn clone(&self) -> Self {
+        *self
+    }
+}
+
+impl<S> Copy for Handle<S> {}
+
+impl<S> From<Handle<S>> for Id {
+    fn from(handle: Handle<S>) -> Self {
+        handle.id
+    }
+}
+
+/// Type-erased deferred execution unit.
+trait Unit {
+    fn resume(&mut self) -> Status;
+    fn can_resume(&self) -> bool;
+    fn state(&self) -> Option<&dyn Any>;
+    fn consume(self: Box<Self>) -> Value;
+}
+
+impl<S: 'static> Unit for Deferred<S> {
+    fn resume(&mut self) -> Status {
+        self.resume_in_place()
+    }
+
+    fn can_resume(&self) -> bool {
+        Deferred::can_resume(self)
+    }
+
+    fn state(&self) -> Option<&dyn Any> {
+        Deferred::state(self).map(|state| state as &dyn Any)
+    }
+
+    fn consume(self: Box<Self>) -> Value {
+        Value::from_box(Box::new((*self).consume()))
+    }
+}
+
+/// Deferred execution manager used to store and resume units of different state types.
+///
+/// Units are resumed in order of their registration.
+///
+/// # Example
+/// ```
+/// # #[macro_use] extern crate deferred;
+/// # use deferred::*;
+/// # fn main() {
+/// fn foo(v: i32) -> Deferred<i32> {
+///     deferred!(v, [
+///         |c| state!(c.state() + 1),
+///         |c| state!(c.state() + 2)
+///     ])
+/// }
+///
+/// fn bar(v: &str) -> Deferred<String> {
+///     deferred!(v.to_owned(), [
+///         |c| state!(format!("{}!", c.state()))
+///     ])
+/// }
+///
+/// let mut manager = AnyDeferredManager::new();
+/// let a = manager.run(foo(1));
+/// let b = manager.run(bar("hello"));
+/// assert_eq!(manager.count(), 2);
+/// manager.resume_all();
+/// assert_eq!(manager.state(a), Some(&2));
+/// assert!(manager.is_finished(b));
+/// manager.resume_all();
+/// assert_eq!(manager.take(a), Some(4));
+/// assert_eq!(manager.take(b), Some("hello!".to_owned()));
+/// assert_eq!(manager.count(), 0);
+/// # }
+/// ```
+#[derive(Default)]
+pub struct AnyDeferredManager {
+    registry: BTreeMap<Id, Box<dyn Unit>>,
+    finished: BTreeMap<Id, Value>,
+    id_generator: Id,
+    cursor: Id,
+}
+
+impl AnyDeferredManager {
+    /// Creates new type-erased deferred execution manager.
+    #[inline]
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Gets number of deferred executions currently waiting to resume.
+    #[inline]
+    pub fn count(&self) -> usize {
+        self.registry.len()
+    }
+
+    /// Register deferred logic for later execution and returns its typed handle.
+    ///
+    /// # Arguments
+    /// * `deferred` - deferred execution unit.
+    pub fn run<S: 'static>(&mut self, deferred: Deferred<S>) -> Handle<S> {
+        let id = self.id_generator;
+        self.id_generator += 1;
+        self.registry.insert(id, Box::new(deferred));
+        Handle {
+            id,
+            _state: PhantomData,
+        }
+    }
+
+    /// Cancel deferred execution unit or drop its finished state.
+    ///
+    /// # Arguments
+    /// * `id` - deferred execution id (got from handle returned by `run()` method).
+    #[inline]
+    pub fn cancel(&mut self, id: Id) -> bool {
+        self.registry.remove(&id).is_some() || self.finished.remove(&id).is_some()
+    }
+
+    /// Tells if deferred execution unit with given id currently waits for later execution.
+    ///
+    /// # Arguments
+    /// * `id` - deferred execution id (got from handle returned by `run()` method).
+    #[inline]
+    pub fn has(&self, id: Id) -> bool {
+        self.registry.contains_key(&id)
+    }
+
+    /// Tells if deferred execution unit has finished and its state waits to be taken.
+    ///
+    /// # Arguments
+    /// * `handle` - deferred execution handle (got from calling `run()` method).
+    #[inline]
+    pub fn is_finished<S>(&self, handle: Handle<S>) -> bool {
+        self.finished.contains_key(&handle.id)
+    }
+
+    /// Gets reference to current state of deferred execution unit, either running or finished.
+    ///
+    /// # Arguments
+    /// * `handle` - deferred execution handle (got from calling `run()` method).
+    pub fn state<S: 'static>(&self, handle: Handle<S>) -> Option<&S> {
+        if let Some(unit) = self.registry.get(&handle.id) {
+            unit.state().and_then(|state| state.downcast_ref::<S>())
+        } else {
+            self.finished
+                .get(&handle.id)
+                .and_then(|state| state.get::<S>())
+        }
+    }
+
+    /// Takes state of finished deferred execution unit.
+    ///
+    /// # Arguments
+    /// * `handle` - deferred execution handle (got from calling `run()` method).
+    pub fn take<S: 'static>(&mut self, handle: Handle<S>) -> Option<S> {
+        self.finished
+            .remove(&handle.id)
+            .and_then(|state| state.take::<S>().ok())
+    }
+
+    /// Resume specified deferred execution unit by its id. Unit that has no parts left gets
+    /// finished without resuming, so its state waits to be taken.
+    ///
+    /// # Arguments
+    /// * `id` - deferred execution id (got from handle returned by `run()` method).
+    pub fn resume(&mut self, id: Id) -> bool {
+        let status = match self.registry.get_mut(&id) {
+            Some(unit) if unit.can_resume() => unit.resume(),
+            Some(_) => Status::Idle,
+            None => return false,
+        };
+        if status != Status::Progressed {
+            if let Some(unit) = self.registry.remove(&id) {
+                self.finished.insert(id, unit.consume());
+            }
+        }
+        status != Status::Idle
+    }
+
+    /// Consume specified deferred execution unit and return its state.
+    ///
+    /// # Arguments
+    /// * `handle` - deferred execution handle (got from calling `run()` method).
+    pub fn consume<S: 'static>(&mut self, handle: Handle<S>) -> Option<S> {
+        if let Some(unit) = self.registry.remove(&handle.id) {
+            unit.consume().take::<S>().ok()
+        } else {
+            self.take(handle)
+        }
+    }
+
+    /// Resumes all deferred execution units in order of their registration.
+    pub fn resume_all(&mut self) {
+        let ids = self.registry.keys().cloned().collect::<Vec<_>>();
+        for id in ids {
+            self.resume(id);
+        }
+    }
+
+    /// Resumes at most `budget` deferred execution units and returns number of resumed ones.
+    ///
+    /// Next call continues from the unit following the last one resumed, so with small budgets
+    /// every unit still gets its turn.
+    ///
+    /// # Arguments
+    /// * `budget` - maximal number of units to resume.
+    ///
+    /// # Example
+    /// ```
+    /// # #[macro_use] extern crate deferred;
+    /// # use deferred::*;
+    /// # fn main() {
+    /// fn foo(v: i32) -> Deferred<i32> {
+    ///     deferred!(v, [
+    ///         |c| state!(c.state() + 1),
+    ///         |c| state!(c.state() + 2)
+    ///     ])
+    /// }
+    ///
+    /// let mut manager = AnyDeferredManager::new();
+    /// let a = manager.run(foo(1));
+    /// let b = manager.run(foo(10));
+    /// assert_eq!(manager.resume_budget(1), 1);
+    /// assert_eq!(manager.state(a), Some(&2));
+    /// assert_eq!(manager.state(b), Some(&10));
+    /// assert_eq!(manager.resume_budget(1), 1);
+    /// assert_eq!(manager.state(b), Some(&11));
+    /// # }
+    /// ```
+    pub fn resume_budget(&mut self, budget: usize) -> usize {
+        let ids = self
+            .registry
+            .range(self.cursor..)
+            .chain(self.registry.range(..self.cursor))
+            .map(|(id, _)| *id)
+            .take(budget)
+            .collect::<Vec<_>>();
+        let resumed = ids.iter().filter(|id| self.resume(**id)).count();
+        if let Some(id) = ids.last() {
+            self.cursor = id + 1;
+        }
+        resumed
+    }
+
+    /// Consume all deferred execution units and return vector of id-state pairs, including states
+    /// of units that have finished before.
+    pub fn consume_all(&mut self) -> Vec<(Id, Value)> {
+        let mut result = core::mem::take(&mut self.finished)
+            .into_iter()
+            .collect::<Vec<_>>();
+        result.extend(
+            core::mem::take(&mut self.registry)
+                .into_iter()
+                .map(|(id, unit)| (id, unit.consume())),
+        );
+        result.sort_by_key(|(id, _)| *id);
+        result
+    }
+}
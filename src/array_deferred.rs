@@ -0,0 +1,203 @@
+use crate::context::*;
+use crate::deferred::*;
+
+/// Subroutine made of static parts, returned from part with `Context::from_static()`. Its parts
+/// get spliced into `ArrayDeferred` storage without any allocation.
+pub trait StaticSubroutine<S: 'static> {
+    /// Logic parts of subroutine.
+    const PARTS: &'static [Part<S>];
+}
+
+pub(crate) fn static_part<S: 'static, T: StaticSubroutine<S>>(index: usize) -> Option<Part<S>> {
+    T::PARTS.get(index).copied()
+}
+
+/// Deferred execution with parts stored in fixed-capacity array instead of heap-allocated queue.
+///
+/// Creating and resuming it never allocates, which makes it a good fit for short-living tasks
+/// created every frame. Subroutines returned by parts get their parts spliced into remaining
+/// capacity - subroutines made with `Context::from_static()` without any allocation, while boxed
+/// ones are released right after that. Items emitted by parts are dropped, since there is no room
+/// to store them.
+///
+/// # Example
+/// ```
+/// # #[macro_use] extern crate deferred;
+/// # use deferred::*;
+/// # fn main() {
+/// fn foo(v: i32) -> ArrayDeferred<i32, 4> {
+///     ArrayDeferred::new(v, &[
+///         |c| state!(c.state() + 1),
+///         |c| foo2(c.state()).into(),
+///         |c| state!(c.state() + 2)
+///     ])
+/// }
+///
+/// fn foo2(v: i32) -> Deferred<i32> {
+///     deferred!(v, [
+///         |c| state!(c.state() * 2),
+///         |c| state!(c.state() * 3)
+///     ])
+/// }
+///
+/// let d = foo(1);
+/// assert_eq!(d.state(), Some(&1));
+/// let d = d.resume().unwrap();
+/// assert_eq!(d.state(), Some(&2));
+/// let d = d.resume().unwrap();
+/// assert_eq!(d.state(), Some(&4));
+/// let d = d.resume().unwrap();
+/// assert_eq!(d.state(), Some(&12));
+/// let d = d.resume().unwrap();
+/// assert_eq!(d.state(), Some(&14));
+/// assert!(!d.can_resume());
+/// # }
+/// ```
+pub struct ArrayDeferred<S, const N: usize> {
+    /// Parts stack - next part to execute is the last one.
+    parts: [Option<Step<S>>; N],
+    len: usize,
+    state: Option<S>,
+}
+
+impl<S, const N: usize> ArrayDeferred<S, N> {
+    /// Creates new fixed-capacity deferred execution.
+    ///
+    /// # Arguments
+    /// * `state` - context initial state.
+    /// * `parts` - slice of logic parts.
+    ///
+    /// # Panics
+    /// * when there are more parts than capacity `N`.
+    pub fn new(state: S, parts: &[Part<S>]) -> Self {
+        assert!(
+            parts.len() <= N,
+            "Trying to create deferred execution of {} parts with capacity of {}",
+            parts.len(),
+            N
+        );
+        let mut result = Self {
+            parts: core::array::from_fn(|_| None),
+            len: 0,
+            state: Some(state),
+        };
+        result.push_parts(parts.iter().cloned().map(Step::new));
+        result
+    }
+
+    /// Gets maximal number of parts that can be stored.
+    #[inline]
+    pub fn capacity(&self) -> usize {
+        N
+    }
+
+    /// Gets number of parts left to execute.
+    #[inline]
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    /// Tells if there are no parts left to execute.
+    #[inline]
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    /// Tells if deferred execution can be resumed.
+    #[inline]
+    pub fn can_resume(&self) -> bool {
+        self.len > 0
+    }
+
+    /// Gets reference to current state stored in context.
+    #[inline]
+    pub fn state(&self) -> Option<&S> {
+        self.state.as_ref()
+    }
+
+    /// Resumes deferred execution, which means we execute next logic part and store its state.
+    ///
+    /// # Panics
+    /// * when subroutine returned by part does not fit into remaining capacity.
+    pub fn resume(mut self) -> Option<Self> {
+        if self.step() {
+            Some(self)
+        } else {
+            None
+        }
+    }
+
+    /// Consumes deferred execution, which means we execute all remaining logic parts and returns
+    /// final state.
+    ///
+    /// # Panics
+    /// * when subroutine returned by part does not fit into remaining capacity.
+    pub fn consume(mut self) -> S {
+        while self.step() {}
+        self.state
+            .expect("Trying to consume deferred execution that has lost its state")
+    }
+
+    /// Alias for `consume()` method.
+    #[inline]
+    pub fn unwrap(self) -> S {
+        self.consume()
+    }
+
+    fn step(&mut self) -> bool {
+        let mut progressed = false;
+        while self.len > 0 {
+            let state = match self.state.take() {
+                Some(state) => state,
+                None => break,
+            };
+            self.len -= 1;
+            let step = self.parts[self.len].take().unwrap();
+            let again = step.again();
+            progressed = true;
+            let (kind, extras) = step.call(Context::from_state(state)).into_parts();
+            match kind {
+                Kind::State(state) => {
+                    self.state = Some(state);
+                    if let (true, Some(again)) = (extras.repeat, again) {
+                        self.parts[self.len] = Some(again);
+                        self.len += 1;
+                    }
+                    break;
+                }
+                Kind::Deferred(deferred) => {
+                    let (state, parts) = deferred.into_parts();
+                    self.state = state;
+                    self.reserve(parts.len());
+                    self.push_parts(parts.into_iter());
+                }
+                Kind::Parts(state, parts) => {
+                    self.state = Some(state);
+                    let count = (0..).map_while(parts).count();
+                    self.reserve(count);
+                    self.push_parts((0..count).filter_map(parts).map(Step::new));
+                }
+            }
+        }
+        progressed
+    }
+
+    fn reserve(&self, count: usize) {
+        assert!(
+            self.len + count <= N,
+            "Trying to splice subroutine of {} parts into deferred execution with {} free slots",
+            count,
+            N - self.len
+        );
+    }
+
+    fn push_parts<I>(&mut self, parts: I)
+    where
+        I: DoubleEndedIterator<Item = Step<S>>,
+    {
+        for step in parts.rev() {
+            self.parts[self.len] = Some(step);
+            self.len += 1;
+        }
+    }
+}
@@ -0,0 +1,312 @@
+use crate::value::Value;
+use alloc::{boxed::Box, collections::BTreeMap};
+use core::{any::TypeId, fmt, marker::PhantomData};
+
+/// Typed key of blackboard entry, used when there are more entries of the same type.
+///
+/// # Example
+/// ```
+/// # use deferred::{Blackboard, Key};
+/// const HEALTH: Key<i32> = Key::new("health");
+/// const MANA: Key<i32> = Key::new("mana");
+///
+/// let mut b = Blackboard::new();
+/// b.insert_key(&HEALTH, 100);
+/// b.insert_key(&MANA, 50);
+/// assert_eq!(b.get_key(&HEALTH), Some(&100));
+/// assert_eq!(b.get_key(&MANA), Some(&50));
+/// assert_eq!(b.get::<i32>(), None);
+/// ```
+pub struct Key<T> {
+    name: &'static str,
+    _type: PhantomData<fn() -> T>,
+}
+
+impl<T> Key<T> {
+    /// Creates new typed key.
+    ///
+    /// # Arguments
+    /// * `name` - key name.
+    pub const fn new(name: &'static str) -> Self {
+        Self {
+            name,
+            _type: PhantomData,
+        }
+    }
+
+    /// Gets key name.
+    #[inline]
+    pub fn name(&self) -> &'static str {
+        self.name
+    }
+}
+
+impl<T> Clone for Key<T> {
+    fn clone(&self) -> Self {
+        *self
+    }
+}
+
+impl<T> Copy for Key<T> {}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+enum Slot {
+    Type(TypeId),
+    Named(&'static str, TypeId),
+}
+
+impl Slot {
+    fn of_type<T: 'static>() -> Self {
+        Slot::Type(TypeId::of::<T>())
+    }
+
+    fn of_key<T: 'static>(key: &Key<T>) -> Self {
+        Slot::Named(key.name, TypeId::of::<T>())
+    }
+}
+
+/// Heterogeneous state bag that lets parts share several typed values, each stored either by its
+/// type or by typed key.
+///
+/// Blackboard can be scoped - scoped blackboard gives access to entries of its parent, so
+/// subroutine can get scoped blackboard as its state and read entries of caller. When subroutine
+/// completes, caller unscopes blackboard to get its own entries back together with the ones
+/// written by subroutine.
+///
+/// # Example
+/// ```
+/// # #[macro_use] extern crate deferred;
+/// # use deferred::*;
+/// # fn main() {
+/// fn foo(v: i32) -> Deferred<Blackboard> {
+///     deferred!(blackboard!(v), [
+///         |c| {
+///             let mut b = c.state();
+///             *b.get_mut::<i32>().unwrap() += 1;
+///             state!(b)
+///         },
+///         |c| foo2(c.state().scope()).into(),
+///         |c| state!(c.state().unscope())
+///     ])
+/// }
+///
+/// fn foo2(b: Blackboard) -> Deferred<Blackboard> {
+///     deferred!(b, [
+///         |c| {
+///             let mut b = c.state();
+///             let text = format!("{}", b.get::<i32>().unwrap());
+///             b.insert(text);
+///             state!(b)
+///         }
+///     ])
+/// }
+///
+/// let mut b = foo(41).consume();
+/// assert_eq!(b.take::<i32>(), Some(42));
+/// assert_eq!(b.take::<String>(), Some("42".to_owned()));
+/// assert!(b.is_empty());
+/// # }
+/// ```
+#[derive(Default)]
+pub struct Blackboard {
+    entries: BTreeMap<Slot, Value>,
+    parent: Option<Box<Blackboard>>,
+}
+
+impl Blackboard {
+    /// Creates new empty blackboard.
+    #[inline]
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Gets number of own entries (without entries of parent).
+    #[inline]
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    /// Tells if blackboard has no own entries.
+    #[inline]
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    /// Gets parent of scoped blackboard.
+    #[inline]
+    pub fn parent(&self) -> Option<&Blackboard> {
+        self.parent.as_deref()
+    }
+
+    /// Consumes blackboard and returns new empty one that gives access to entries of consumed one.
+    pub fn scope(self) -> Self {
+        Self {
+            entries: BTreeMap::new(),
+            parent: Some(Box::new(self)),
+        }
+    }
+
+    /// Consumes scoped blackboard and returns its parent with own entries moved into it. If it is
+    /// not scoped, it is returned unchanged.
+    pub fn unscope(mut self) -> Self {
+        match self.parent.take() {
+            Some(mut parent) => {
+                parent.entries.append(&mut self.entries);
+                *parent
+            }
+            None => self,
+        }
+    }
+
+    /// Puts entry stored by its type and returns previous one, if there was any.
+    ///
+    /// # Arguments
+    /// * `value` - entry value.
+    pub fn insert<T: 'static>(&mut self, value: T) -> Option<T> {
+        self.insert_slot(Slot::of_type::<T>(), value)
+    }
+
+    /// Consumes blackboard and returns it with entry stored by its type.
+    ///
+    /// # Arguments
+    /// * `value` - entry value.
+    pub fn with<T: 'static>(mut self, value: T) -> Self {
+        self.insert(value);
+        self
+    }
+
+    /// Puts already wrapped value as entry stored by its type and returns previous one, if there
+    /// was any.
+    ///
+    /// # Arguments
+    /// * `value` - entry value.
+    pub fn insert_value(&mut self, value: Value) -> Option<Value> {
+        self.entries.insert(Slot::Type(value.type_id()), value)
+    }
+
+    /// Tells if there is entry of given type, either own or of parent.
+    #[inline]
+    pub fn has<T: 'static>(&self) -> bool {
+        self.get::<T>().is_some()
+    }
+
+    /// Gets reference to entry of given type, either own or of parent.
+    #[inline]
+    pub fn get<T: 'static>(&self) -> Option<&T> {
+        self.get_slot(&Slot::of_type::<T>())
+    }
+
+    /// Gets mutable reference to entry of given type, either own or of parent.
+    #[inline]
+    pub fn get_mut<T: 'static>(&mut self) -> Option<&mut T> {
+        self.get_slot_mut(&Slot::of_type::<T>())
+    }
+
+    /// Takes entry of given type out of blackboard, either own or of parent.
+    #[inline]
+    pub fn take<T: 'static>(&mut self) -> Option<T> {
+        self.take_slot(&Slot::of_type::<T>())
+    }
+
+    /// Puts entry stored by typed key and returns previous one, if there was any.
+    ///
+    /// # Arguments
+    /// * `key` - entry key.
+    /// * `value` - entry value.
+    pub fn insert_key<T: 'static>(&mut self, key: &Key<T>, value: T) -> Option<T> {
+        self.insert_slot(Slot::of_key(key), value)
+    }
+
+    /// Consumes blackboard and returns it with entry stored by typed key.
+    ///
+    /// # Arguments
+    /// * `key` - entry key.
+    /// * `value` - entry value.
+    pub fn with_key<T: 'static>(mut self, key: &Key<T>, value: T) -> Self {
+        self.insert_key(key, value);
+        self
+    }
+
+    /// Tells if there is entry with given key, either own or of parent.
+    #[inline]
+    pub fn has_key<T: 'static>(&self, key: &Key<T>) -> bool {
+        self.get_key(key).is_some()
+    }
+
+    /// Gets reference to entry with given key, either own or of parent.
+    #[inline]
+    pub fn get_key<T: 'static>(&self, key: &Key<T>) -> Option<&T> {
+        self.get_slot(&Slot::of_key(key))
+    }
+
+    /// Gets mutable reference to entry with given key, either own or of parent.
+    #[inline]
+    pub fn get_key_mut<T: 'static>(&mut self, key: &Key<T>) -> Option<&mut T> {
+        self.get_slot_mut(&Slot::of_key(key))
+    }
+
+    /// Takes entry with given key out of blackboard, either own or of parent.
+    #[inline]
+    pub fn take_key<T: 'static>(&mut self, key: &Key<T>) -> Option<T> {
+        self.take_slot(&Slot::of_key(key))
+    }
+
+    fn insert_slot<T: 'static>(&mut self, slot: Slot, value: T) -> Option<T> {
+        self.entries
+            .insert(slot, Value::from_box(Box::new(value)))
+            .and_then(|value| value.take::<T>().ok())
+    }
+
+    fn get_slot<T: 'static>(&self, slot: &Slot) -> Option<&T> {
+        let mut blackboard = Some(self);
+        while let Some(b) = blackboard {
+            if let Some(value) = b.entries.get(slot) {
+                return value.get::<T>();
+            }
+            blackboard = b.parent.as_deref();
+        }
+        None
+    }
+
+    fn get_slot_mut<T: 'static>(&mut self, slot: &Slot) -> Option<&mut T> {
+        let mut blackboard = Some(self);
+        while let Some(b) = blackboard {
+            if b.entries.contains_key(slot) {
+                return b
+                    .entries
+                    .get_mut(slot)
+                    .and_then(|value| value.get_mut::<T>());
+            }
+            blackboard = b.parent.as_deref_mut();
+        }
+        None
+    }
+
+    fn take_slot<T: 'static>(&mut self, slot: &Slot) -> Option<T> {
+        let mut blackboard = Some(self);
+        while let Some(b) = blackboard {
+            if let Some(value) = b.entries.remove(slot) {
+                return value.take::<T>().ok();
+            }
+            blackboard = b.parent.as_deref_mut();
+        }
+        None
+    }
+}
+
+impl fmt::Debug for Blackboard {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let mut map = f.debug_map();
+        for (slot, value) in &self.entries {
+            match slot {
+                Slot::Type(_) => map.entry(&value.type_name(), value),
+                Slot::Named(name, _) => map.entry(name, value),
+            };
+        }
+        map.finish()?;
+        if let Some(parent) = &self.parent {
+            write!(f, " <- {:?}", parent)?;
+        }
+        Ok(())
+    }
+}
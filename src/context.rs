@@ -1,18 +1,228 @@
+use crate::array_deferred::{static_part, StaticSubroutine};
 use crate::deferred::*;
+use crate::deferred_manager::{Id, Tick};
+use crate::resources::Resources;
+use crate::spawner::Spawner;
+use crate::value::{SendValue, Value};
+use alloc::{borrow::Cow, boxed::Box, vec::Vec};
 
-/// Deferred execution context holds its state or inner deferred execution (if there is deferred
-/// subroutine needed to evaluate).
-pub enum Context<S> {
-    /// Context holds single state.
+/// Name of event that parts can wait for.
+pub type Event = Cow<'static, str>;
+
+/// Information about environment in which part is executed, got from `Context::env()`.
+/// `DeferredManager` fills in unit id and times on each resume, while execution resumed on its own
+/// has no id and all times equal to zero.
+///
+/// # Example
+/// ```
+/// # #[macro_use] extern crate deferred;
+/// # use deferred::*;
+/// # fn main() {
+/// fn sub(c: Context<Vec<usize>>) -> Context<Vec<usize>> {
+///     deferred!(c.state(), [|c| {
+///         let depth = c.env().depth;
+///         let mut v = c.state();
+///         v.push(depth);
+///         state!(v)
+///     }]).into()
+/// }
+///
+/// let mut manager = DeferredManager::new();
+/// manager.advance_time(10);
+/// let id = manager.run(deferred!(vec![], [
+///     sub,
+///     |c| {
+///         let env = *c.env();
+///         let mut v = c.state();
+///         v.extend(vec![env.id.unwrap(), env.steps, env.elapsed() as usize]);
+///         state!(v)
+///     },
+///     |c| state!(c.state())
+/// ]));
+/// manager.resume(id);
+/// manager.advance_time(5);
+/// manager.resume(id);
+/// assert_eq!(manager.state(id), Some(&vec![1, id, 2, 5]));
+/// # }
+/// ```
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct Env {
+    /// Id of unit in `DeferredManager`.
+    pub id: Option<Id>,
+    /// Current time of `DeferredManager`.
+    pub time: Tick,
+    /// Time when unit was registered in `DeferredManager`.
+    pub started: Tick,
+    /// Number of parts executed before this one, including parts that produced subroutines.
+    pub steps: usize,
+    /// Number of nested subroutines this part runs in, zero for parts of the unit itself.
+    pub depth: usize,
+}
+
+impl Env {
+    /// Gets time that has passed since unit was registered.
+    #[inline]
+    pub fn elapsed(&self) -> Tick {
+        self.time.saturating_sub(self.started)
+    }
+}
+
+/// Services of `DeferredManager` lent to parts only for the duration of their call, so units never
+/// keep them alive on their own.
+pub(crate) struct Host<'a, S> {
+    pub(crate) spawner: &'a Spawner<S>,
+    pub(crate) resources: &'a Resources,
+}
+
+/// What context holds - either state or deferred subroutine.
+pub(crate) enum Kind<S> {
     State(S),
-    /// Context holds deferred subroutine needed to evaluate.
     Deferred(Box<Deferred<S>>),
+    /// Subroutine made of static parts (got by index), so it can be spliced without allocation.
+    Parts(S, fn(usize) -> Option<Part<S>>),
+}
+
+impl<S> Kind<S> {
+    /// Gets state or subroutine to evaluate, making `Deferred` of static subroutine.
+    pub(crate) fn into_state(self) -> Result<S, Box<Deferred<S>>> {
+        match self {
+            Kind::State(state) => Ok(state),
+            Kind::Deferred(deferred) => Err(deferred),
+            Kind::Parts(state, parts) => Err(Box::new(Deferred::new(
+                state,
+                (0..).map_while(parts).collect(),
+            ))),
+        }
+    }
+}
+
+/// Data returned from part together with state.
+#[derive(Default)]
+pub(crate) struct Extras {
+    pub(crate) emitted: Vec<SendValue>,
+    pub(crate) wait: Option<Event>,
+    /// Tells if part that has produced this context should be executed again on next resume.
+    pub(crate) repeat: bool,
+}
+
+/// Deferred execution context holds its state or inner deferred execution (if there is deferred
+/// subroutine needed to evaluate).
+///
+/// Context passed to part can also hold input injected by host with `resume_with()`, while
+/// context returned from part can hold items emitted with `emit()` and event to wait for set with
+/// `wait_for()`.
+pub struct Context<S> {
+    kind: Kind<S>,
+    input: Option<Value>,
+    spawner: Option<Spawner<S>>,
+    resources: Option<Resources>,
+    env: Env,
+    extras: Extras,
 }
 
 impl<S> Context<S> {
+    /// Creates context holding single state.
+    ///
+    /// # Arguments
+    /// * `state` - state.
+    #[inline]
+    pub fn from_state(state: S) -> Self {
+        Self {
+            kind: Kind::State(state),
+            input: None,
+            spawner: None,
+            resources: None,
+            env: Env::default(),
+            extras: Extras::default(),
+        }
+    }
+
+    /// Creates context holding deferred subroutine needed to evaluate.
+    ///
+    /// # Arguments
+    /// * `deferred` - deferred subroutine.
+    #[inline]
+    pub fn from_deferred(deferred: Deferred<S>) -> Self {
+        Self {
+            kind: Kind::Deferred(Box::new(deferred)),
+            input: None,
+            spawner: None,
+            resources: None,
+            env: Env::default(),
+            extras: Extras::default(),
+        }
+    }
+
+    /// Creates context holding subroutine made of static parts of `T`. Unlike `from_deferred()`
+    /// it does not allocate, so `ArrayDeferred` can splice it into its own storage as is.
+    ///
+    /// # Arguments
+    /// * `state` - subroutine initial state.
+    ///
+    /// # Example
+    /// ```
+    /// # #[macro_use] extern crate deferred;
+    /// # use deferred::*;
+    /// # fn main() {
+    /// struct Scale;
+    ///
+    /// impl StaticSubroutine<i32> for Scale {
+    ///     const PARTS: &'static [Part<i32>] = &[
+    ///         |c| state!(c.state() * 2),
+    ///         |c| state!(c.state() * 3)
+    ///     ];
+    /// }
+    ///
+    /// fn foo(v: i32) -> ArrayDeferred<i32, 3> {
+    ///     ArrayDeferred::new(v, &[
+    ///         |c| Context::from_static::<Scale>(c.state()),
+    ///         |c| state!(c.state() + 1)
+    ///     ])
+    /// }
+    ///
+    /// assert_eq!(foo(1).consume(), 7);
+    /// # }
+    /// ```
+    #[inline]
+    pub fn from_static<T>(state: S) -> Self
+    where
+        S: 'static,
+        T: StaticSubroutine<S>,
+    {
+        Self {
+            kind: Kind::Parts(state, static_part::<S, T>),
+            input: None,
+            spawner: None,
+            resources: None,
+            env: Env::default(),
+            extras: Extras::default(),
+        }
+    }
+
+    pub(crate) fn with_input(
+        state: S,
+        input: Option<Value>,
+        host: Option<&Host<S>>,
+        env: Env,
+    ) -> Self {
+        Self {
+            kind: Kind::State(state),
+            input,
+            spawner: host.map(|host| host.spawner.clone()),
+            resources: host.map(|host| host.resources.clone()),
+            env,
+            extras: Extras::default(),
+        }
+    }
+
+    pub(crate) fn into_parts(self) -> (Kind<S>, Extras) {
+        (self.kind, self.extras)
+    }
+
     /// Tells if context holds a state.
+    #[allow(clippy::match_like_matches_macro)]
     pub fn is_state(&self) -> bool {
-        if let Context::State(_) = self {
+        if let Kind::State(_) = self.kind {
             true
         } else {
             false
@@ -20,36 +230,219 @@ impl<S> Context<S> {
     }
 
     /// Tells if context holds a deferred subroutine to evaluate.
+    #[allow(clippy::match_like_matches_macro)]
     pub fn is_deferred(&self) -> bool {
-        if let Context::Deferred(_) = self {
-            true
-        } else {
-            false
+        match self.kind {
+            Kind::Deferred(_) | Kind::Parts(..) => true,
+            Kind::State(_) => false,
         }
     }
 
     /// Gets reference to current state if there is one hold by context or its deferred subroutine.
     pub fn get_state(&self) -> Option<&S> {
-        match self {
-            Context::State(state) => Some(state),
-            Context::Deferred(deferred) => deferred.state(),
+        match &self.kind {
+            Kind::State(state) | Kind::Parts(state, _) => Some(state),
+            Kind::Deferred(deferred) => deferred.state(),
         }
     }
 
-    /// Gets deferred subroutine if context has one.
+    /// Gets deferred subroutine if context has one. Subroutine made of static parts is not
+    /// a `Deferred` yet, so it gives `None`.
     pub fn get_deferred(&self) -> Option<&Deferred<S>> {
-        if let Context::Deferred(deferred) = self {
+        if let Kind::Deferred(deferred) = &self.kind {
             Some(deferred)
         } else {
             None
         }
     }
 
+    /// Tells if context holds input injected by host.
+    #[inline]
+    pub fn has_input(&self) -> bool {
+        self.input.is_some()
+    }
+
+    /// Gets reference to input of given type injected by host, or `None` if there is no input or
+    /// it is of another type.
+    ///
+    /// # Example
+    /// ```
+    /// # #[macro_use] extern crate deferred;
+    /// # use deferred::*;
+    /// # fn main() {
+    /// let d = deferred!(0, [|c| {
+    ///     let choice = c.input::<i32>().cloned().unwrap_or(-1);
+    ///     state!(c.state() + choice)
+    /// }]);
+    /// assert_eq!(d.resume_with(42).unwrap().state(), Some(&42));
+    /// # }
+    /// ```
+    #[inline]
+    pub fn input<I: 'static>(&self) -> Option<&I> {
+        self.input.as_ref().and_then(|input| input.get::<I>())
+    }
+
+    /// Takes input of given type injected by host out of context, or returns `None` if there is
+    /// no input or it is of another type.
+    pub fn take_input<I: 'static>(&mut self) -> Option<I> {
+        match self.input.take()?.take::<I>() {
+            Ok(input) => Some(input),
+            Err(input) => {
+                self.input = Some(input);
+                None
+            }
+        }
+    }
+
+    /// Gets information about environment in which part is executed.
+    #[inline]
+    pub fn env(&self) -> &Env {
+        &self.env
+    }
+
+    /// Gets spawner of `DeferredManager` that runs this execution, used to start new units from
+    /// inside of part. Execution that is not run by manager has no spawner.
+    #[inline]
+    pub fn spawner(&self) -> Option<&Spawner<S>> {
+        self.spawner.as_ref()
+    }
+
+    /// Starts new unit as child of the one running this execution and returns its id, or `None`
+    /// when execution is not run by `DeferredManager`.
+    ///
+    /// # Arguments
+    /// * `deferred` - deferred execution unit.
+    pub fn run_child(&self, deferred: Deferred<S>) -> Option<Id> {
+        let spawner = self.spawner.as_ref()?;
+        Some(spawner.run_child(self.env.id?, deferred))
+    }
+
+    /// Gets resources shared by units of `DeferredManager` that runs this execution. Execution
+    /// that is not run by manager has no resources.
+    #[inline]
+    pub fn resources(&self) -> Option<&Resources> {
+        self.resources.as_ref()
+    }
+
+    /// Calls closure with shared resource of given type and returns its result, or `None` when
+    /// there is no such resource.
+    ///
+    /// # Arguments
+    /// * `f` - closure getting resource.
+    pub fn resource<T, R, F>(&self, f: F) -> Option<R>
+    where
+        T: 'static,
+        F: FnOnce(&T) -> R,
+    {
+        self.resources.as_ref()?.with(f)
+    }
+
+    /// Calls closure with mutable shared resource of given type and returns its result, or `None`
+    /// when there is no such resource.
+    ///
+    /// # Arguments
+    /// * `f` - closure getting mutable resource.
+    pub fn resource_mut<T, R, F>(&self, f: F) -> Option<R>
+    where
+        T: 'static,
+        F: FnOnce(&mut T) -> R,
+    {
+        self.resources.as_ref()?.with_mut(f)
+    }
+
+    /// Consumes context and returns it with emitted item, which can be then taken from deferred
+    /// execution without changing its state. Call it on context returned from part.
+    ///
+    /// # Arguments
+    /// * `item` - emitted item.
+    ///
+    /// # Example
+    /// ```
+    /// # #[macro_use] extern crate deferred;
+    /// # use deferred::*;
+    /// # fn main() {
+    /// fn search(from: usize, to: usize) -> Deferred<usize> {
+    ///     let mut builder = DeferredBuilder::new(from);
+    ///     for _ in from..to {
+    ///         builder = builder.then_part(|c| {
+    ///             let v = c.state();
+    ///             if v % 7 == 0 {
+    ///                 state!(v + 1).emit(v)
+    ///             } else {
+    ///                 state!(v + 1)
+    ///             }
+    ///         });
+    ///     }
+    ///     builder.build()
+    /// }
+    ///
+    /// let mut d = search(1, 30);
+    /// assert_eq!(d.items::<usize>().collect::<Vec<_>>(), vec![7, 14, 21, 28]);
+    /// assert_eq!(d.state(), Some(&30));
+    /// # }
+    /// ```
+    pub fn emit<T: Send + 'static>(mut self, item: T) -> Self {
+        self.extras
+            .emitted
+            .push(SendValue::from_box(Box::new(item)));
+        self
+    }
+
+    /// Gets number of items emitted with this context.
+    #[inline]
+    pub fn emitted_count(&self) -> usize {
+        self.extras.emitted.len()
+    }
+
+    /// Consumes context and returns it with event that execution waits for. `DeferredManager`
+    /// does not resume waiting unit until that event gets signaled. Call it on context returned
+    /// from part.
+    ///
+    /// # Arguments
+    /// * `event` - event name.
+    ///
+    /// # Example
+    /// ```
+    /// # #[macro_use] extern crate deferred;
+    /// # use deferred::*;
+    /// # fn main() {
+    /// let mut manager = DeferredManager::new();
+    /// let id = manager.run(deferred!(0, [
+    ///     |c| state!(c.state() + 1).wait_for("door_opened"),
+    ///     |c| state!(c.state() + 1)
+    /// ]));
+    /// manager.resume_all();
+    /// assert!(!manager.has_runnable());
+    /// manager.resume_all();
+    /// assert_eq!(manager.state(id), Some(&1));
+    /// assert_eq!(manager.signal("door_opened"), 1);
+    /// manager.resume_all();
+    /// assert!(!manager.has(id));
+    /// # }
+    /// ```
+    pub fn wait_for<E: Into<Event>>(mut self, event: E) -> Self {
+        self.extras.wait = Some(event.into());
+        self
+    }
+
+    /// Consumes context and returns it with request to execute the same logic part again on next
+    /// resume, which lets part yield without allocating a subroutine.
+    pub(crate) fn again(mut self) -> Self {
+        self.extras.repeat = true;
+        self
+    }
+
+    /// Gets event that execution will wait for.
+    #[inline]
+    pub fn waits_for(&self) -> Option<&str> {
+        self.extras.wait.as_deref()
+    }
+
     /// Consumes context and returns its state.
     pub fn state(self) -> S {
-        match self {
-            Context::State(state) => state,
-            Context::Deferred(deferred) => deferred.consume(),
+        match self.kind.into_state() {
+            Ok(state) => state,
+            Err(deferred) => deferred.consume(),
         }
     }
 
@@ -59,10 +452,11 @@ impl<S> Context<S> {
     /// * when context does not hold deferred subroutine so you should make sure about that by
     ///   calling `self.is_deferred()` before gettin context deferred subroutine.
     pub fn deferred(self) -> Deferred<S> {
-        if let Context::Deferred(deferred) = self {
-            *deferred
-        } else {
-            panic!("Trying to get deferred execution of context that does not have a deferred execution")
+        match self.kind.into_state() {
+            Err(deferred) => *deferred,
+            Ok(_) => panic!(
+                "Trying to get deferred execution of context that does not have a deferred execution"
+            ),
         }
     }
 
@@ -0,0 +1,312 @@
+use crate::deferred::*;
+use alloc::vec::Vec;
+
+/// Place where `DebugDeferred` pauses execution.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Breakpoint {
+    /// Pause before executing part with given name.
+    Name(&'static str),
+    /// Pause before executing step with given index (number of resumes done so far).
+    Index(usize),
+}
+
+/// Reason why `DebugDeferred::run()` has stopped.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Stop {
+    /// Execution has reached breakpoint.
+    Breakpoint(Breakpoint),
+    /// Execution has completed.
+    Completed,
+}
+
+/// Single recorded state of debugged deferred execution.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Record<S> {
+    /// Number of resumes done before this state was produced.
+    pub index: usize,
+    /// Name of the part that has produced this state, if it has one.
+    pub part: Option<&'static str>,
+    /// Recorded state.
+    pub state: S,
+}
+
+/// Place where two recordings differ, found by `Recording::diff()`.
+#[derive(Debug, PartialEq)]
+pub struct Divergence<'a, S> {
+    /// Index of the first differing record.
+    pub index: usize,
+    /// Record of this recording or `None` if it is shorter.
+    pub left: Option<&'a Record<S>>,
+    /// Record of other recording or `None` if it is shorter.
+    pub right: Option<&'a Record<S>>,
+}
+
+/// Sequence of states produced by debugged deferred execution, starting with initial state.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Recording<S> {
+    records: Vec<Record<S>>,
+}
+
+impl<S> Recording<S> {
+    /// Gets all records.
+    #[inline]
+    pub fn records(&self) -> &[Record<S>] {
+        &self.records
+    }
+
+    /// Gets number of records.
+    #[inline]
+    pub fn len(&self) -> usize {
+        self.records.len()
+    }
+
+    /// Tells if there are no records.
+    #[inline]
+    pub fn is_empty(&self) -> bool {
+        self.records.is_empty()
+    }
+
+    /// Gets iterator over recorded states in order they were produced, used to replay execution.
+    pub fn replay(&self) -> impl Iterator<Item = &S> {
+        self.records.iter().map(|record| &record.state)
+    }
+
+    /// Consumes recording and returns its records.
+    #[inline]
+    pub fn into_records(self) -> Vec<Record<S>> {
+        self.records
+    }
+}
+
+impl<S: PartialEq> Recording<S> {
+    /// Finds first place where this and other recording differ, or `None` if they are equal.
+    ///
+    /// # Arguments
+    /// * `other` - recording to compare with.
+    pub fn diff<'a>(&'a self, other: &'a Recording<S>) -> Option<Divergence<'a, S>> {
+        let len = self.records.len().max(other.records.len());
+        (0..len)
+            .map(|index| Divergence {
+                index,
+                left: self.records.get(index),
+                right: other.records.get(index),
+            })
+            .find(|divergence| divergence.left != divergence.right)
+    }
+}
+
+/// Wrapper over deferred execution that lets you step it in controlled way, pause it on
+/// breakpoints and records every state it produces.
+///
+/// # Example
+/// ```
+/// # #[macro_use] extern crate deferred;
+/// # use deferred::*;
+/// # fn main() {
+/// fn foo(v: i32) -> Deferred<i32> {
+///     deferred!(v, [
+///         |c| state!(c.state() + 1),
+///         "double" => |c| state!(c.state() * 2),
+///         |c| state!(c.state() - 3)
+///     ])
+/// }
+///
+/// let mut d = DebugDeferred::new(foo(1));
+/// d.add_breakpoint(Breakpoint::Name("double"));
+/// assert_eq!(d.run(), Stop::Breakpoint(Breakpoint::Name("double")));
+/// assert_eq!(d.state(), Some(&2));
+/// assert!(d.step());
+/// assert_eq!(d.state(), Some(&4));
+/// assert_eq!(d.run(), Stop::Completed);
+///
+/// let mut expected = DebugDeferred::new(foo(2));
+/// expected.run();
+/// let divergence = d.recording().diff(expected.recording()).unwrap();
+/// assert_eq!(divergence.index, 0);
+/// assert_eq!(d.recording().replay().collect::<Vec<_>>(), vec![&1, &2, &4, &1]);
+/// # }
+/// ```
+pub struct DebugDeferred<S> {
+    deferred: Deferred<S>,
+    breakpoints: Vec<Breakpoint>,
+    recording: Recording<S>,
+    steps: usize,
+    /// Tells if execution is paused at breakpoint that matches next step.
+    paused: bool,
+}
+
+impl<S: Clone> DebugDeferred<S> {
+    /// Creates new debugged deferred execution and records its initial state.
+    ///
+    /// # Arguments
+    /// * `deferred` - deferred execution to debug.
+    pub fn new(deferred: Deferred<S>) -> Self {
+        let records = deferred
+            .state()
+            .map(|state| Record {
+                index: 0,
+                part: None,
+                state: state.clone(),
+            })
+            .into_iter()
+            .collect();
+        Self {
+            deferred,
+            breakpoints: Vec::new(),
+            recording: Recording { records },
+            steps: 0,
+            paused: false,
+        }
+    }
+
+    /// Gets debugged deferred execution.
+    #[inline]
+    pub fn deferred(&self) -> &Deferred<S> {
+        &self.deferred
+    }
+
+    /// Gets current state.
+    #[inline]
+    pub fn state(&self) -> Option<&S> {
+        self.deferred.state()
+    }
+
+    /// Tells if execution can be resumed.
+    #[inline]
+    pub fn can_resume(&self) -> bool {
+        self.deferred.can_resume()
+    }
+
+    /// Gets number of resumes done so far.
+    #[inline]
+    pub fn steps(&self) -> usize {
+        self.steps
+    }
+
+    /// Gets recording of states produced so far.
+    #[inline]
+    pub fn recording(&self) -> &Recording<S> {
+        &self.recording
+    }
+
+    /// Gets breakpoints.
+    #[inline]
+    pub fn breakpoints(&self) -> &[Breakpoint] {
+        &self.breakpoints
+    }
+
+    /// Adds breakpoint.
+    ///
+    /// # Arguments
+    /// * `breakpoint` - breakpoint.
+    pub fn add_breakpoint(&mut self, breakpoint: Breakpoint) {
+        if !self.breakpoints.contains(&breakpoint) {
+            self.breakpoints.push(breakpoint);
+        }
+    }
+
+    /// Removes breakpoint and tells if it was there.
+    ///
+    /// # Arguments
+    /// * `breakpoint` - breakpoint.
+    pub fn remove_breakpoint(&mut self, breakpoint: Breakpoint) -> bool {
+        let count = self.breakpoints.len();
+        self.breakpoints.retain(|b| *b != breakpoint);
+        count != self.breakpoints.len()
+    }
+
+    /// Removes all breakpoints.
+    pub fn clear_breakpoints(&mut self) {
+        self.breakpoints.clear();
+    }
+
+    /// Gets breakpoint that matches next step, if there is any.
+    pub fn breakpoint(&self) -> Option<Breakpoint> {
+        if !self.deferred.can_resume() {
+            return None;
+        }
+        let name = self.deferred.next_part_name();
+        self.breakpoints.iter().cloned().find(|b| match b {
+            Breakpoint::Name(n) => name == Some(*n),
+            Breakpoint::Index(i) => *i == self.steps,
+        })
+    }
+
+    /// Resumes execution once, ignoring breakpoints, and records produced state. Returns `false`
+    /// if execution cannot be resumed.
+    pub fn step(&mut self) -> bool {
+        self.paused = false;
+        self.advance(false)
+    }
+
+    /// Resumes execution until next step matches breakpoint or execution completes. Breakpoint
+    /// that execution is paused at is skipped, so calling it repeatedly moves from one breakpoint
+    /// to the next one, while breakpoint matching first step stops execution before it. Name
+    /// breakpoints also match parts of subroutines, which pauses execution right after their
+    /// parts get spliced in.
+    pub fn run(&mut self) -> Stop {
+        if self.paused && !self.step() {
+            return Stop::Completed;
+        }
+        loop {
+            if let Some(breakpoint) = self.breakpoint() {
+                self.paused = true;
+                return Stop::Breakpoint(breakpoint);
+            }
+            if !self.advance(true) {
+                return Stop::Completed;
+            }
+        }
+    }
+
+    /// Executes parts one by one until one of them produces a state and records it together with
+    /// name of that part. When `pause` is set, stops earlier if subroutine got spliced in front of
+    /// part that matches name breakpoint.
+    fn advance(&mut self, pause: bool) -> bool {
+        let mut progressed = false;
+        let mut part = None;
+        loop {
+            let next = self.deferred.next_part_name();
+            match self.deferred.step_part() {
+                Some(produced) => {
+                    progressed = true;
+                    part = next;
+                    if produced {
+                        break;
+                    }
+                }
+                None => break,
+            }
+            if pause {
+                if let Some(name) = self.deferred.next_part_name() {
+                    if self.breakpoints.contains(&Breakpoint::Name(name)) {
+                        return true;
+                    }
+                }
+            }
+        }
+        if !progressed {
+            return false;
+        }
+        self.steps += 1;
+        if let Some(state) = self.deferred.state() {
+            self.recording.records.push(Record {
+                index: self.steps,
+                part,
+                state: state.clone(),
+            });
+        }
+        true
+    }
+
+    /// Consumes debugger and returns recording of all states, executing remaining parts.
+    pub fn record(mut self) -> Recording<S> {
+        while self.step() {}
+        self.recording
+    }
+
+    /// Consumes debugger and returns debugged deferred execution with its recording.
+    pub fn into_inner(self) -> (Deferred<S>, Recording<S>) {
+        (self.deferred, self.recording)
+    }
+}
@@ -1,18 +1,174 @@
 use crate::context::*;
-use std::collections::VecDeque;
+use crate::limits::*;
+use crate::value::{SendValue, Value};
+use alloc::{boxed::Box, collections::VecDeque, vec, vec::Vec};
 
 /// Alias for deferred logic part that takes current context and produces new one that will be
 /// passed to next deferred step execution.
 pub type Part<S> = fn(input: Context<S>) -> Context<S>;
 
+/// Observer attached with `Deferred::inspect()`, together with number of queued parts it still
+/// observes.
+struct Inspector<S> {
+    observer: Box<dyn FnMut(&S) + Send>,
+    parts: usize,
+}
+
+enum StepKind<S> {
+    Part(Part<S>),
+    Closure(Box<dyn FnOnce(Context<S>) -> Context<S> + Send>),
+    Deferred(Box<Deferred<S>>),
+}
+
+/// Single step of deferred execution: either logic part or closure that can capture values,
+/// with optional name used for diagnostics. Closures have to be `Send`, so deferred execution can
+/// be sent to another thread whenever its state can.
+///
+/// # Example
+/// ```
+/// # #[macro_use] extern crate deferred;
+/// # use deferred::*;
+/// # fn main() {
+/// fn foo(v: i32, n: i32) -> Deferred<i32> {
+///     Deferred::from_steps(v, vec![
+///         Step::new(|c| state!(c.state() + 1)).named("increment"),
+///         Step::closure(move |c| state!(c.state() * n)).named("multiply"),
+///     ])
+/// }
+///
+/// let d = foo(1, 3);
+/// assert_eq!(d.next_part_name(), Some("increment"));
+/// let d = d.resume().unwrap();
+/// assert_eq!(d.next_part_name(), Some("multiply"));
+/// assert_eq!(d.consume(), 6);
+/// # }
+/// ```
+pub struct Step<S> {
+    name: Option<&'static str>,
+    kind: StepKind<S>,
+    /// Indices of inspectors of deferred execution that observe states produced by this step.
+    inspectors: Vec<usize>,
+}
+
+impl<S> Step<S> {
+    /// Creates new step from logic part.
+    ///
+    /// # Arguments
+    /// * `part` - logic part.
+    pub fn new(part: Part<S>) -> Self {
+        Self {
+            name: None,
+            kind: StepKind::Part(part),
+            inspectors: Vec::new(),
+        }
+    }
+
+    /// Creates new step from closure.
+    ///
+    /// # Arguments
+    /// * `closure` - closure that takes current context and produces new one.
+    pub fn closure<F>(closure: F) -> Self
+    where
+        F: FnOnce(Context<S>) -> Context<S> + Send + 'static,
+    {
+        Self {
+            name: None,
+            kind: StepKind::Closure(Box::new(closure)),
+            inspectors: Vec::new(),
+        }
+    }
+
+    /// Creates new step that replaces current state with given deferred execution and runs it as
+    /// subroutine.
+    fn deferred(deferred: Deferred<S>) -> Self {
+        Self {
+            name: None,
+            kind: StepKind::Deferred(Box::new(deferred)),
+            inspectors: Vec::new(),
+        }
+    }
+
+    /// Consumes step and returns it with given name.
+    ///
+    /// # Arguments
+    /// * `name` - step name.
+    pub fn named(mut self, name: &'static str) -> Self {
+        self.name = Some(name);
+        self
+    }
+
+    /// Gets step name.
+    #[inline]
+    pub fn name(&self) -> Option<&'static str> {
+        self.name
+    }
+
+    /// Copy of this step to execute again when it asks for that, possible only for logic parts.
+    pub(crate) fn again(&self) -> Option<Self> {
+        match self.kind {
+            StepKind::Part(part) => Some(Self {
+                name: self.name,
+                kind: StepKind::Part(part),
+                inspectors: Vec::new(),
+            }),
+            _ => None,
+        }
+    }
+
+    /// Consumes step and executes it on given context.
+    ///
+    /// # Arguments
+    /// * `context` - context to execute step on.
+    pub fn call(self, context: Context<S>) -> Context<S> {
+        match self.kind {
+            StepKind::Part(part) => part(context),
+            StepKind::Closure(closure) => closure(context),
+            StepKind::Deferred(deferred) => Context::from_deferred(*deferred),
+        }
+    }
+}
+
+impl<S> From<Part<S>> for Step<S> {
+    fn from(part: Part<S>) -> Self {
+        Self::new(part)
+    }
+}
+
+/// Result of resuming deferred execution in place.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Status {
+    /// Part was executed and there are more parts to execute.
+    Progressed,
+    /// Last part was executed.
+    Completed,
+    /// Nothing was executed because execution has already completed.
+    Idle,
+}
+
 /// Struct that holds parts and state of deferred logic to execute whenever you want to.
 ///
 /// # Note
 /// Everytime when you want to resume execution, you consume deferred context and produce new one
-/// so keep in mind to restore it before `resume()` and store it again after `resume()`.
+/// so keep in mind to restore it before `resume()` and store it again after `resume()`. When it is
+/// stored behind mutable reference, use `resume_in_place()` instead.
+///
+/// When part panics, only that part and state passed to it are lost - remaining parts are kept
+/// but execution cannot be resumed anymore since there is no state to pass to them. Use
+/// `resume_in_place_preserving()` to keep copy of that state instead.
+///
+/// Deferred subroutines returned by parts are not stored as nested contexts - their parts are
+/// spliced in front of remaining parts instead, so resuming even very deeply nested chains never
+/// recurses and cannot overflow the stack.
 pub struct Deferred<S> {
-    parts: VecDeque<Part<S>>,
-    context: Context<S>,
+    parts: VecDeque<Step<S>>,
+    state: Option<S>,
+    pub(crate) emitted: VecDeque<SendValue>,
+    pub(crate) waiting: Option<Event>,
+    watchdog: Option<Box<Watchdog>>,
+    tracker: Tracker,
+    pub(crate) env: Env,
+    inspectors: Vec<Option<Inspector<S>>>,
+    cleanup: Option<Box<dyn FnOnce(S) + Send>>,
 }
 
 impl<S> Deferred<S> {
@@ -38,14 +194,186 @@ impl<S> Deferred<S> {
     /// # }
     /// ```
     pub fn new(state: S, parts: Vec<Part<S>>) -> Self {
-        let mut p = VecDeque::new();
-        p.extend(parts);
         Self {
-            parts: p,
-            context: Context::State(state),
+            parts: parts.into_iter().map(Step::new).collect(),
+            state: Some(state),
+            emitted: VecDeque::new(),
+            waiting: None,
+            watchdog: None,
+            tracker: Tracker::default(),
+            env: Env::default(),
+            inspectors: Vec::new(),
+            cleanup: None,
+        }
+    }
+
+    /// Creates new deferred execution from steps.
+    ///
+    /// # Arguments
+    /// * `state` - context initial state.
+    /// * `steps` - vector of steps.
+    pub fn from_steps(state: S, steps: Vec<Step<S>>) -> Self {
+        Self {
+            parts: steps.into(),
+            state: Some(state),
+            emitted: VecDeque::new(),
+            waiting: None,
+            watchdog: None,
+            tracker: Tracker::default(),
+            env: Env::default(),
+            inspectors: Vec::new(),
+            cleanup: None,
         }
     }
 
+    /// Consumes deferred execution and returns it with given limits. When any of them is
+    /// exceeded, execution stops, its remaining parts are dropped and `limit_exceeded()` tells
+    /// which limit it was.
+    ///
+    /// Limits belong to execution they are set on. Limits of subroutine returned by part are
+    /// ignored, since its parts get spliced into caller and count against limits of caller.
+    ///
+    /// # Arguments
+    /// * `limits` - limits of execution.
+    pub fn with_limits(mut self, limits: Limits) -> Self {
+        let clock = self.watchdog.and_then(|watchdog| watchdog.clock);
+        self.watchdog = Some(Box::new(Watchdog::new(limits, clock)));
+        self
+    }
+
+    /// Consumes deferred execution and returns it with clock used to measure time of executing
+    /// parts against `Limits::max_duration`. Clock is kept only by execution that has limits, so
+    /// it has to be set after `with_limits()`.
+    ///
+    /// # Arguments
+    /// * `clock` - clock giving time passed since any fixed moment.
+    ///
+    /// # Example
+    /// ```
+    /// # #[macro_use] extern crate deferred;
+    /// # use deferred::*;
+    /// # use std::sync::atomic::{AtomicU64, Ordering};
+    /// # use std::time::Duration;
+    /// # fn main() {
+    /// static NOW: AtomicU64 = AtomicU64::new(0);
+    ///
+    /// fn slow(c: Context<i32>) -> Context<i32> {
+    ///     NOW.fetch_add(10, Ordering::Relaxed);
+    ///     deferred!(c.state() + 1, [slow]).into()
+    /// }
+    ///
+    /// let limit = Duration::from_millis(25);
+    /// let mut d = deferred!(0, [slow])
+    ///     .with_limits(Limits {
+    ///         max_duration: Some(limit),
+    ///         ..Default::default()
+    ///     })
+    ///     .with_clock(|| Duration::from_millis(NOW.load(Ordering::Relaxed)));
+    /// d.resume_in_place();
+    /// assert_eq!(d.limit_exceeded(), Some(LimitExceeded::Duration(limit)));
+    /// assert_eq!(d.state(), Some(&3));
+    /// # }
+    /// ```
+    pub fn with_clock(mut self, clock: Clock) -> Self {
+        if let Some(watchdog) = self.watchdog.as_mut() {
+            watchdog.clock = Some(clock);
+        }
+        self
+    }
+
+    /// Tells if execution has limits but no clock of its own.
+    pub(crate) fn needs_clock(&self) -> bool {
+        self.watchdog
+            .as_ref()
+            .is_some_and(|watchdog| watchdog.clock.is_none())
+    }
+
+    /// Consumes deferred execution and returns it with cleanup function called with its state when
+    /// `DeferredManager` cancels it (directly or together with its parent) or when it fails.
+    /// Execution which part has panicked has lost its state together with that part, so its
+    /// cleanup is not called then.
+    ///
+    /// # Arguments
+    /// * `cleanup` - cleanup function.
+    pub fn on_cancel<F>(mut self, cleanup: F) -> Self
+    where
+        F: FnOnce(S) + Send + 'static,
+    {
+        self.cleanup = Some(Box::new(cleanup));
+        self
+    }
+
+    /// Gets limits of execution, if it has any.
+    #[inline]
+    pub fn limits(&self) -> Option<&Limits> {
+        self.watchdog.as_ref().map(|watchdog| &watchdog.limits)
+    }
+
+    /// Gets limit that has stopped execution, if any.
+    #[inline]
+    pub fn limit_exceeded(&self) -> Option<LimitExceeded> {
+        self.watchdog
+            .as_ref()
+            .and_then(|watchdog| watchdog.exceeded)
+    }
+
+    /// Gets event that execution waits for, set by last executed part with
+    /// `Context::wait_for()`. Resuming execution clears it.
+    #[inline]
+    pub fn waiting_for(&self) -> Option<&str> {
+        self.waiting.as_deref()
+    }
+
+    /// Stops waiting if execution waits for given event and tells if it did.
+    ///
+    /// # Arguments
+    /// * `event` - signaled event name.
+    pub fn signal(&mut self, event: &str) -> bool {
+        if self.waiting.as_deref() == Some(event) {
+            self.waiting = None;
+            true
+        } else {
+            false
+        }
+    }
+
+    /// Gets number of emitted items waiting to be taken.
+    #[inline]
+    pub fn emitted_count(&self) -> usize {
+        self.emitted.len()
+    }
+
+    /// Takes all items emitted so far by executed parts.
+    ///
+    /// # Example
+    /// ```
+    /// # #[macro_use] extern crate deferred;
+    /// # use deferred::*;
+    /// # fn main() {
+    /// let d = deferred!(0, [
+    ///     |c| state!(c.state() + 1).emit("one").emit(1),
+    ///     |c| state!(c.state() + 1)
+    /// ]);
+    /// let mut d = d.resume().unwrap();
+    /// let items = d.drain_emitted().collect::<Vec<_>>();
+    /// assert_eq!(items[0].get::<&str>(), Some(&"one"));
+    /// assert_eq!(items[1].get::<i32>(), Some(&1));
+    /// assert_eq!(d.emitted_count(), 0);
+    /// # }
+    /// ```
+    pub fn drain_emitted(&mut self) -> impl Iterator<Item = Value> + '_ {
+        self.emitted.drain(..).map(Value::from)
+    }
+
+    /// Gets iterator over emitted items of given type that resumes execution whenever there are
+    /// no emitted items waiting to be taken.
+    ///
+    /// # Panics
+    /// * when emitted item is of another type.
+    pub fn items<T: 'static>(&mut self) -> impl Iterator<Item = T> + '_ {
+        Iterator::map(self, Value::consume::<T>)
+    }
+
     /// Tells if deferred execution can be resumed.
     ///
     /// # Example
@@ -69,10 +397,7 @@ impl<S> Deferred<S> {
     /// # }
     /// ```
     pub fn can_resume(&self) -> bool {
-        match &self.context {
-            Context::State(_) => !self.parts.is_empty(),
-            Context::Deferred(d) => d.can_resume() || !self.parts.is_empty(),
-        }
+        self.state.is_some() && !self.parts.is_empty()
     }
 
     /// Gets reference to current state stored in context.
@@ -98,7 +423,80 @@ impl<S> Deferred<S> {
     /// # }
     /// ```
     pub fn state(&self) -> Option<&S> {
-        self.context.get_state()
+        self.state.as_ref()
+    }
+
+    /// Gets mutable reference to current state stored in context.
+    #[inline]
+    pub fn state_mut(&mut self) -> Option<&mut S> {
+        self.state.as_mut()
+    }
+
+    /// Gets name of the part that will be executed on next resume, if it has one.
+    #[inline]
+    pub fn next_part_name(&self) -> Option<&'static str> {
+        self.parts.front().and_then(|step| step.name)
+    }
+
+    /// Gets number of parts left to execute, including parts of subroutines already started.
+    #[inline]
+    pub fn remaining_parts(&self) -> usize {
+        self.parts.len()
+    }
+
+    /// Appends logic part or closure to the end of running execution, so it will be executed
+    /// after all remaining parts.
+    ///
+    /// # Arguments
+    /// * `part` - logic part or closure.
+    ///
+    /// # Example
+    /// ```
+    /// # #[macro_use] extern crate deferred;
+    /// # use deferred::*;
+    /// # fn main() {
+    /// let mut d = deferred!(1, [|c| state!(c.state() + 1)]).resume().unwrap();
+    /// assert!(!d.can_resume());
+    /// d.push(|c| state!(c.state() * 10));
+    /// d.push_front_step(Step::new(|c| state!(c.state() + 2)).named("add"));
+    /// assert_eq!(d.next_part_name(), Some("add"));
+    /// assert_eq!(d.remaining_parts(), 2);
+    /// assert_eq!(d.consume(), 40);
+    /// # }
+    /// ```
+    pub fn push<F>(&mut self, part: F)
+    where
+        F: FnOnce(Context<S>) -> Context<S> + Send + 'static,
+    {
+        self.push_step(Step::closure(part));
+    }
+
+    /// Appends step to the end of running execution.
+    ///
+    /// # Arguments
+    /// * `step` - step.
+    pub fn push_step(&mut self, step: Step<S>) {
+        self.parts.push_back(step);
+    }
+
+    /// Puts logic part or closure in front of remaining parts, so it will be executed on next
+    /// resume.
+    ///
+    /// # Arguments
+    /// * `part` - logic part or closure.
+    pub fn push_front<F>(&mut self, part: F)
+    where
+        F: FnOnce(Context<S>) -> Context<S> + Send + 'static,
+    {
+        self.push_front_step(Step::closure(part));
+    }
+
+    /// Puts step in front of remaining parts, so it will be executed on next resume.
+    ///
+    /// # Arguments
+    /// * `step` - step.
+    pub fn push_front_step(&mut self, step: Step<S>) {
+        self.parts.push_front(step);
     }
 
     /// Resumes deferred execution, which means we execute next logic part and store its state.
@@ -149,37 +547,226 @@ impl<S> Deferred<S> {
     /// # }
     /// ```
     pub fn resume(mut self) -> Option<Self> {
-        match self.context {
-            Context::State(state) => {
-                if let Some(part) = self.parts.pop_front() {
-                    let context = part(Context::State(state));
-                    if context.is_deferred() {
-                        self.context = context;
-                        self.resume()
-                    } else {
-                        self.context = context;
-                        Some(self)
-                    }
-                } else {
-                    None
-                }
-            }
-            Context::Deferred(deferred) => {
-                if deferred.can_resume() {
-                    if let Some(deferred) = deferred.resume() {
-                        self.context = deferred.into();
-                        Some(self)
-                    } else {
-                        None
-                    }
-                } else {
-                    self.context = Context::State(deferred.consume());
-                    self.resume()
-                }
-            }
+        if self.step() {
+            Some(self)
+        } else {
+            None
+        }
+    }
+
+    /// Resumes deferred execution without consuming it and tells what has happened.
+    ///
+    /// State is moved into executed part, so when that part panics execution cannot keep it and
+    /// is left without state, which means it cannot be resumed anymore. Use
+    /// `resume_in_place_preserving()` when state has to survive a panic.
+    ///
+    /// # Example
+    /// ```
+    /// # #[macro_use] extern crate deferred;
+    /// # use deferred::*;
+    /// # fn main() {
+    /// struct Actor {
+    ///     task: Deferred<i32>,
+    /// }
+    ///
+    /// let mut actor = Actor {
+    ///     task: deferred!(1, [
+    ///         |c| state!(c.state() + 1),
+    ///         |c| state!(c.state() + 2)
+    ///     ]),
+    /// };
+    /// assert_eq!(actor.task.resume_in_place(), Status::Progressed);
+    /// *actor.task.state_mut().unwrap() *= 10;
+    /// assert_eq!(actor.task.resume_in_place(), Status::Completed);
+    /// assert_eq!(actor.task.resume_in_place(), Status::Idle);
+    /// assert_eq!(actor.task.state(), Some(&22));
+    /// # }
+    /// ```
+    pub fn resume_in_place(&mut self) -> Status {
+        self.resume_hosted(None, None)
+    }
+
+    /// Resumes deferred execution without consuming it, keeping copy of state passed to each
+    /// executed part. When part panics, execution gets that state back, so after catching panic
+    /// it can be inspected or resumed with parts that follow the panicking one.
+    ///
+    /// # Example
+    /// ```
+    /// # #[macro_use] extern crate deferred;
+    /// # use deferred::*;
+    /// # fn main() {
+    /// let mut d = deferred!(1, [
+    ///     |c| -> Context<i32> { panic!("failed with {}", c.state()) },
+    ///     |c| state!(c.state() + 1)
+    /// ]);
+    /// let result = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| {
+    ///     d.resume_in_place_preserving()
+    /// }));
+    /// assert!(result.is_err());
+    /// assert_eq!(d.state(), Some(&1));
+    /// assert_eq!(d.resume_in_place_preserving(), Status::Completed);
+    /// assert_eq!(d.state(), Some(&2));
+    /// # }
+    /// ```
+    pub fn resume_in_place_preserving(&mut self) -> Status
+    where
+        S: Clone,
+    {
+        let progressed = self
+            .step_preserving(None, None, Some(S::clone), false)
+            .is_some();
+        self.status(progressed)
+    }
+
+    /// Resumes deferred execution passing given input to the next executed part, which can read
+    /// it with `Context::input()`. Next executed part always belongs to the innermost running
+    /// subroutine, so input is routed to it. Input is given only to that part - when it produces
+    /// subroutine, input is dropped together with its context and does not reach parts of that
+    /// subroutine, so part has to pass it in state of subroutine if they need it.
+    ///
+    /// # Arguments
+    /// * `input` - input for the next part.
+    ///
+    /// # Example
+    /// ```
+    /// # #[macro_use] extern crate deferred;
+    /// # use deferred::*;
+    /// # fn main() {
+    /// fn ask(v: i32) -> Deferred<i32> {
+    ///     deferred!(v, [
+    ///         |c| subdeferred!(c.state(), [|c| state!(c.state())]),
+    ///         |mut c| {
+    ///             let answer = c.take_input::<String>().unwrap();
+    ///             state!(c.state() + answer.len() as i32)
+    ///         }
+    ///     ])
+    /// }
+    ///
+    /// let d = ask(1).resume().unwrap();
+    /// let d = d.resume_with("yes".to_owned()).unwrap();
+    /// assert_eq!(d.state(), Some(&4));
+    /// # }
+    /// ```
+    pub fn resume_with<I: 'static>(mut self, input: I) -> Option<Self> {
+        if self.step_with(Some(Value::from_box(Box::new(input))), None) {
+            Some(self)
+        } else {
+            None
+        }
+    }
+
+    /// Resumes deferred execution in place passing given input to the next executed part.
+    ///
+    /// # Arguments
+    /// * `input` - input for the next part.
+    pub fn resume_in_place_with<I: 'static>(&mut self, input: I) -> Status {
+        self.resume_hosted(Some(Value::from_box(Box::new(input))), None)
+    }
+
+    /// Resumes deferred execution in place with services of given host available to the part.
+    pub(crate) fn resume_hosted(&mut self, input: Option<Value>, host: Option<&Host<S>>) -> Status {
+        let progressed = self.step_with(input, host);
+        self.status(progressed)
+    }
+
+    fn status(&self, progressed: bool) -> Status {
+        if !progressed {
+            Status::Idle
+        } else if self.can_resume() {
+            Status::Progressed
+        } else {
+            Status::Completed
         }
     }
 
+    /// Resumes deferred execution at most `n` times, stopping earlier when it completes or starts
+    /// waiting for event.
+    ///
+    /// # Arguments
+    /// * `n` - maximal number of resumes.
+    ///
+    /// # Example
+    /// ```
+    /// # #[macro_use] extern crate deferred;
+    /// # use deferred::*;
+    /// # fn main() {
+    /// let d = deferred!(0, [
+    ///     |c| state!(c.state() + 1),
+    ///     |c| state!(c.state() + 1),
+    ///     |c| state!(c.state() + 1)
+    /// ]);
+    /// let d = d.resume_n(2);
+    /// assert_eq!(d.state(), Some(&2));
+    /// let d = d.resume_n(10);
+    /// assert_eq!(d.state(), Some(&3));
+    /// assert!(!d.can_resume());
+    /// # }
+    /// ```
+    pub fn resume_n(mut self, n: usize) -> Self {
+        self.step_n(n, None, &mut 0);
+        self
+    }
+
+    /// Resumes deferred execution as long as predicate holds for current state, or until it
+    /// completes or starts waiting for event.
+    ///
+    /// # Arguments
+    /// * `predicate` - tells if execution should be resumed with given state.
+    ///
+    /// # Example
+    /// ```
+    /// # #[macro_use] extern crate deferred;
+    /// # use deferred::*;
+    /// # fn main() {
+    /// let d = deferred!(0, [
+    ///     |c| state!(c.state() + 1),
+    ///     |c| state!(c.state() + 1),
+    ///     |c| state!(c.state() + 1)
+    /// ]);
+    /// let d = d.resume_while(|v| *v < 2);
+    /// assert_eq!(d.state(), Some(&2));
+    /// assert!(d.can_resume());
+    /// # }
+    /// ```
+    pub fn resume_while<F>(mut self, predicate: F) -> Self
+    where
+        F: FnMut(&S) -> bool,
+    {
+        self.step_while(predicate, None, &mut 0);
+        self
+    }
+
+    /// Resumes deferred execution until it produces state that satisfies predicate, or until it
+    /// completes or starts waiting for event. Execution is resumed at least once, if it can be resumed.
+    ///
+    /// # Arguments
+    /// * `predicate` - tells if produced state is the one we wait for.
+    ///
+    /// # Example
+    /// ```
+    /// # #[macro_use] extern crate deferred;
+    /// # use deferred::*;
+    /// # fn main() {
+    /// let d = deferred!(0, [
+    ///     |c| state!(c.state() + 1),
+    ///     |c| state!(c.state() + 1),
+    ///     |c| state!(c.state() + 1)
+    /// ]);
+    /// let d = d.resume_until_state(|v| v % 2 == 0);
+    /// assert_eq!(d.state(), Some(&2));
+    /// let d = d.resume_until_state(|v| v % 2 == 0);
+    /// assert_eq!(d.state(), Some(&3));
+    /// assert!(!d.can_resume());
+    /// # }
+    /// ```
+    pub fn resume_until_state<F>(mut self, predicate: F) -> Self
+    where
+        F: FnMut(&S) -> bool,
+    {
+        self.step_until_state(predicate, None, &mut 0);
+        self
+    }
+
     /// Consumes deferred execution, which means we execute all remaining logic parts and returns
     /// final state.
     ///
@@ -199,10 +786,9 @@ impl<S> Deferred<S> {
     /// # }
     /// ```
     pub fn consume(mut self) -> S {
-        while self.can_resume() {
-            self = self.resume().unwrap();
-        }
-        self.context.state()
+        while self.step() {}
+        self.state
+            .expect("Trying to consume deferred execution that has lost its state")
     }
 
     /// Alias for `consume()` method.
@@ -210,10 +796,548 @@ impl<S> Deferred<S> {
     pub fn unwrap(self) -> S {
         self.consume()
     }
+
+    /// Executes parts until one of them produces a state. Subroutines produced on the way get
+    /// their parts spliced in front of remaining ones and are executed in the same loop.
+    pub(crate) fn step(&mut self) -> bool {
+        self.step_with(None, None)
+    }
+
+    /// Same as `step()` but passes given input to the first executed part and lends services of
+    /// given host to executed parts.
+    pub(crate) fn step_with(&mut self, input: Option<Value>, host: Option<&Host<S>>) -> bool {
+        self.step_preserving(input, host, None, false).is_some()
+    }
+
+    /// Executes single part, also when it produces subroutine, and tells if that part has
+    /// produced a state. Returns `None` if no part was executed.
+    pub(crate) fn step_part(&mut self) -> Option<bool> {
+        self.step_preserving(None, None, None, true)
+    }
+
+    /// Same as `step_with()` but when given clone function, keeps copy of state passed to each
+    /// part and puts it back when that part panics. When `single` is set, stops after first
+    /// executed part.
+    fn step_preserving(
+        &mut self,
+        input: Option<Value>,
+        host: Option<&Host<S>>,
+        preserve: Option<fn(&S) -> S>,
+        single: bool,
+    ) -> Option<bool> {
+        self.waiting = None;
+        match self.watchdog.take() {
+            Some(mut watchdog) => {
+                let produced =
+                    self.step_tracked(Some(&mut watchdog), input, host, preserve, single);
+                self.watchdog = Some(watchdog);
+                produced
+            }
+            None => self.step_tracked(None, input, host, preserve, single),
+        }
+    }
+
+    /// Executes parts keeping track of steps and depth, checking limits after every executed part
+    /// when there is a watchdog. Tells if last executed part has produced a state, or returns
+    /// `None` if no part was executed.
+    fn step_tracked(
+        &mut self,
+        mut watchdog: Option<&mut Watchdog>,
+        mut input: Option<Value>,
+        host: Option<&Host<S>>,
+        preserve: Option<fn(&S) -> S>,
+        single: bool,
+    ) -> Option<bool> {
+        let clock = watchdog
+            .as_ref()
+            .and_then(|watchdog| watchdog.duration_clock());
+        let mut started = clock.map(|clock| clock());
+        let mut progressed = false;
+        let mut produced = false;
+        while !self.parts.is_empty() {
+            let state = match self.state.take() {
+                Some(state) => state,
+                None => break,
+            };
+            if let Some(watchdog) = watchdog.as_mut() {
+                if let Err(exceeded) = watchdog.check_steps(self.tracker.steps) {
+                    self.state = Some(state);
+                    self.exceed(watchdog, exceeded);
+                    produced = true;
+                    break;
+                }
+            }
+            let env = Env {
+                steps: self.tracker.steps,
+                depth: self.tracker.depth(),
+                ..self.env
+            };
+            self.tracker.steps += 1;
+            let mut step = self.parts.pop_front().unwrap();
+            let inspectors = core::mem::take(&mut step.inspectors);
+            let again = step.again();
+            progressed = true;
+            let restore = Restore {
+                slot: &mut self.state,
+                state: preserve.map(|clone| clone(&state)),
+                inspectors: &mut self.inspectors,
+                released: Some(&inspectors),
+            };
+            let (kind, extras) = step
+                .call(Context::with_input(state, input.take(), host, env))
+                .into_parts();
+            restore.disarm();
+            let repeat = extras.repeat;
+            self.absorb(extras);
+            let result = match kind.into_state() {
+                Ok(state) => {
+                    self.inspect_state(&inspectors, &state);
+                    self.state = Some(state);
+                    if let (true, Some(mut again)) = (repeat, again) {
+                        self.observe(&inspectors);
+                        again.inspectors = inspectors.clone();
+                        self.parts.push_front(again);
+                    }
+                    self.tracker.after_state(self.parts.len());
+                    Ok(true)
+                }
+                Err(deferred) => {
+                    let queued = self.parts.len();
+                    self.splice(*deferred, &inspectors);
+                    self.tracker.after_subroutine(queued, self.parts.len());
+                    match watchdog.as_ref() {
+                        Some(watchdog) => watchdog
+                            .check_subroutine(self.tracker.depth(), self.parts.len())
+                            .map(|_| false),
+                        None => Ok(false),
+                    }
+                }
+            };
+            self.release_inspectors(&inspectors);
+            let result = match (clock, started.as_mut(), watchdog.as_mut()) {
+                (Some(clock), Some(started), Some(watchdog)) => result.and_then(|done| {
+                    let now = clock();
+                    watchdog.add_elapsed(now.saturating_sub(*started))?;
+                    *started = now;
+                    Ok(done)
+                }),
+                _ => result,
+            };
+            match result {
+                Ok(true) => {
+                    produced = true;
+                    break;
+                }
+                Ok(false) if !single => {}
+                Ok(false) => break,
+                Err(exceeded) => {
+                    if let Some(watchdog) = watchdog.as_mut() {
+                        self.exceed(watchdog, exceeded);
+                    }
+                    produced = true;
+                    break;
+                }
+            }
+        }
+        progressed.then_some(produced)
+    }
+
+    fn exceed(&mut self, watchdog: &mut Watchdog, exceeded: LimitExceeded) {
+        watchdog.exceeded = Some(exceeded);
+        self.parts.clear();
+        self.inspectors.clear();
+    }
+
+    /// Resumes execution until `steps` counter reaches `n`. Resumes are counted as they complete,
+    /// so the count is known also when one of them panics.
+    pub(crate) fn step_n(&mut self, n: usize, host: Option<&Host<S>>, steps: &mut usize) {
+        while *steps < n && self.step_with(None, host) {
+            *steps += 1;
+            if self.waiting.is_some() {
+                break;
+            }
+        }
+    }
+
+    pub(crate) fn step_while<F>(
+        &mut self,
+        mut predicate: F,
+        host: Option<&Host<S>>,
+        steps: &mut usize,
+    ) where
+        F: FnMut(&S) -> bool,
+    {
+        while self.state.as_ref().is_some_and(&mut predicate) && self.step_with(None, host) {
+            *steps += 1;
+            if self.waiting.is_some() {
+                break;
+            }
+        }
+    }
+
+    pub(crate) fn step_until_state<F>(
+        &mut self,
+        mut predicate: F,
+        host: Option<&Host<S>>,
+        steps: &mut usize,
+    ) where
+        F: FnMut(&S) -> bool,
+    {
+        while self.step_with(None, host) {
+            *steps += 1;
+            if self.waiting.is_some() || self.state.as_ref().is_some_and(&mut predicate) {
+                break;
+            }
+        }
+    }
+
+    /// Consumes deferred execution and calls its cleanup function if it still has its state.
+    pub(crate) fn cleanup(self) {
+        if let (Some(cleanup), Some(state)) = (self.cleanup, self.state) {
+            cleanup(state);
+        }
+    }
+
+    pub(crate) fn into_parts(self) -> (Option<S>, VecDeque<Step<S>>) {
+        (self.state, self.parts)
+    }
+
+    fn absorb(&mut self, extras: Extras) {
+        self.emitted.extend(extras.emitted);
+        if extras.wait.is_some() {
+            self.waiting = extras.wait;
+        }
+    }
+
+    /// Puts parts of subroutine in front of remaining parts. Its inspectors are moved to this
+    /// execution and its parts get also observed by inspectors of the part that produced it.
+    fn splice(&mut self, deferred: Deferred<S>, inherited: &[usize]) {
+        self.state = deferred.state;
+        self.emitted.extend(deferred.emitted);
+        let offset = self.inspectors.len();
+        self.inspectors.extend(deferred.inspectors);
+        for index in inherited {
+            if let Some(Some(inspector)) = self.inspectors.get_mut(*index) {
+                inspector.parts += deferred.parts.len();
+            }
+        }
+        for mut part in deferred.parts.into_iter().rev() {
+            for index in part.inspectors.iter_mut() {
+                *index += offset;
+            }
+            part.inspectors.extend_from_slice(inherited);
+            self.parts.push_front(part);
+        }
+    }
+
+    fn inspect_state(&mut self, indices: &[usize], state: &S) {
+        for index in indices {
+            if let Some(Some(inspector)) = self.inspectors.get_mut(*index) {
+                (inspector.observer)(state);
+            }
+        }
+    }
+
+    /// Attaches inspectors to part that gets executed again.
+    fn observe(&mut self, indices: &[usize]) {
+        for index in indices {
+            if let Some(Some(inspector)) = self.inspectors.get_mut(*index) {
+                inspector.parts += 1;
+            }
+        }
+    }
+
+    /// Detaches inspectors from executed part and drops the ones that do not observe any part.
+    fn release_inspectors(&mut self, indices: &[usize]) {
+        release_inspectors(&mut self.inspectors, indices);
+    }
+}
+
+fn release_inspectors<S>(inspectors: &mut Vec<Option<Inspector<S>>>, indices: &[usize]) {
+    for index in indices {
+        if let Some(slot) = inspectors.get_mut(*index) {
+            if let Some(inspector) = slot {
+                inspector.parts -= 1;
+                if inspector.parts == 0 {
+                    *slot = None;
+                }
+            }
+        }
+    }
+    while let Some(None) = inspectors.last() {
+        inspectors.pop();
+    }
 }
 
+/// Cleans up after part that panics - puts copy of state passed to it (if there is one) back into
+/// execution and detaches inspectors from that part.
+struct Restore<'a, S> {
+    slot: &'a mut Option<S>,
+    state: Option<S>,
+    inspectors: &'a mut Vec<Option<Inspector<S>>>,
+    released: Option<&'a [usize]>,
+}
+
+impl<S> Restore<'_, S> {
+    /// Called when part has returned, so there is nothing to clean up.
+    fn disarm(mut self) {
+        self.state = None;
+        self.released = None;
+    }
+}
+
+impl<S> Drop for Restore<'_, S> {
+    fn drop(&mut self) {
+        if let Some(state) = self.state.take() {
+            *self.slot = Some(state);
+        }
+        if let Some(indices) = self.released.take() {
+            release_inspectors(self.inspectors, indices);
+        }
+    }
+}
+
+#[allow(clippy::from_over_into)]
 impl<S> Into<Context<S>> for Deferred<S> {
     fn into(self) -> Context<S> {
-        Context::Deferred(Box::new(self))
+        Context::from_deferred(self)
+    }
+}
+
+/// Deferred execution is an iterator over items emitted by its parts, resuming it whenever there
+/// are no emitted items waiting to be taken. Iterator adapters with names of `Deferred` methods
+/// (like `map()` or `zip()`) have to be called as `Iterator::map(d, ...)`.
+impl<S> Iterator for Deferred<S> {
+    type Item = Value;
+
+    fn next(&mut self) -> Option<Value> {
+        loop {
+            if let Some(item) = self.emitted.pop_front() {
+                return Some(item.into());
+            }
+            if !self.step() {
+                return None;
+            }
+        }
+    }
+}
+
+impl<S> Extend<Step<S>> for Deferred<S> {
+    fn extend<I: IntoIterator<Item = Step<S>>>(&mut self, steps: I) {
+        self.parts.extend(steps);
+    }
+}
+
+impl<S: 'static> Deferred<S> {
+    /// Consumes deferred execution and returns new one that continues with another deferred
+    /// execution when this one completes. Final state of this execution gets replaced by initial
+    /// state of the other one.
+    ///
+    /// # Arguments
+    /// * `other` - deferred execution to continue with.
+    ///
+    /// # Example
+    /// ```
+    /// # #[macro_use] extern crate deferred;
+    /// # use deferred::*;
+    /// # fn main() {
+    /// let a = deferred!(1, [|c| state!(c.state() + 1)]);
+    /// let b = deferred!(10, [|c| state!(c.state() * 2)]);
+    /// let d = a.then(b).resume().unwrap();
+    /// assert_eq!(d.state(), Some(&2));
+    /// let d = d.resume().unwrap();
+    /// assert_eq!(d.state(), Some(&20));
+    /// assert!(!d.can_resume());
+    /// # }
+    /// ```
+    pub fn then(mut self, other: Deferred<S>) -> Self {
+        self.push_step(Step::deferred(other));
+        self
+    }
+
+    /// Consumes deferred execution and returns new one that transforms final state with given
+    /// function. Transformation is executed as separate step.
+    ///
+    /// # Arguments
+    /// * `f` - state transformation.
+    ///
+    /// # Example
+    /// ```
+    /// # #[macro_use] extern crate deferred;
+    /// # use deferred::*;
+    /// # fn main() {
+    /// let d = deferred!(1, [|c| state!(c.state() + 1)]).map(|v| v * 10);
+    /// assert_eq!(d.consume(), 20);
+    /// # }
+    /// ```
+    pub fn map<F>(mut self, f: F) -> Self
+    where
+        F: FnOnce(S) -> S + Send + 'static,
+    {
+        self.push(move |c| Context::from_state(f(c.state())));
+        self
+    }
+
+    /// Consumes deferred execution and returns new one that continues with deferred execution
+    /// created from final state of this one.
+    ///
+    /// # Arguments
+    /// * `f` - function that creates next deferred execution from final state.
+    ///
+    /// # Example
+    /// ```
+    /// # #[macro_use] extern crate deferred;
+    /// # use deferred::*;
+    /// # fn main() {
+    /// fn foo(v: i32) -> Deferred<i32> {
+    ///     deferred!(v, [|c| state!(c.state() * 2)])
+    /// }
+    ///
+    /// let d = deferred!(1, [|c| state!(c.state() + 1)]).and_then(foo);
+    /// let d = d.resume().unwrap();
+    /// assert_eq!(d.state(), Some(&2));
+    /// let d = d.resume().unwrap();
+    /// assert_eq!(d.state(), Some(&4));
+    /// assert!(!d.can_resume());
+    /// # }
+    /// ```
+    pub fn and_then<F>(mut self, f: F) -> Self
+    where
+        F: FnOnce(S) -> Deferred<S> + Send + 'static,
+    {
+        self.push(move |c| f(c.state()).into());
+        self
+    }
+
+    /// Consumes deferred execution and returns new one that calls given function with every
+    /// state produced by resuming it, including states produced by subroutines.
+    ///
+    /// # Arguments
+    /// * `f` - function that observes produced states.
+    ///
+    /// # Example
+    /// ```
+    /// # #[macro_use] extern crate deferred;
+    /// # use deferred::*;
+    /// # use std::sync::{Arc, Mutex};
+    /// # fn main() {
+    /// fn foo(v: i32) -> Deferred<i32> {
+    ///     deferred!(v, [
+    ///         |c| state!(c.state() + 1),
+    ///         |c| foo2(c.state()).into()
+    ///     ])
+    /// }
+    ///
+    /// fn foo2(v: i32) -> Deferred<i32> {
+    ///     deferred!(v, [|c| state!(c.state() * 2)])
+    /// }
+    ///
+    /// let log = Arc::new(Mutex::new(vec![]));
+    /// let log2 = log.clone();
+    /// let d = foo(1).inspect(move |v| log2.lock().unwrap().push(*v));
+    /// assert_eq!(d.consume(), 4);
+    /// assert_eq!(*log.lock().unwrap(), vec![2, 4]);
+    /// # }
+    /// ```
+    pub fn inspect<F>(mut self, f: F) -> Self
+    where
+        F: FnMut(&S) + Send + 'static,
+    {
+        let index = self.inspectors.len();
+        for step in self.parts.iter_mut() {
+            step.inspectors.push(index);
+        }
+        self.inspectors.push(Some(Inspector {
+            observer: Box::new(f),
+            parts: self.parts.len(),
+        }));
+        self
+    }
+
+    /// Consumes deferred execution and returns new one that resumes both this and other deferred
+    /// execution in lockstep - every resume of zipped execution resumes each of them once, as long
+    /// as it can be resumed.
+    ///
+    /// # Arguments
+    /// * `other` - deferred execution to resume together with this one.
+    ///
+    /// # Example
+    /// ```
+    /// # #[macro_use] extern crate deferred;
+    /// # use deferred::*;
+    /// # fn main() {
+    /// let a = deferred!(1, [
+    ///     |c| state!(c.state() + 1),
+    ///     |c| state!(c.state() + 1)
+    /// ]);
+    /// let b = deferred!("a".to_owned(), [|c| state!(c.state() + "b")]);
+    /// let d = a.zip(b).resume().unwrap();
+    /// assert_eq!(d.state().unwrap().states(), (Some(&2), Some(&"ab".to_owned())));
+    /// let d = d.resume().unwrap();
+    /// assert!(!d.can_resume());
+    /// assert_eq!(d.consume().into_states(), (3, "ab".to_owned()));
+    /// # }
+    /// ```
+    pub fn zip<T: 'static>(self, other: Deferred<T>) -> Deferred<Zip<S, T>> {
+        let can_resume = self.can_resume() || other.can_resume();
+        let zip = Zip {
+            left: self,
+            right: other,
+        };
+        if can_resume {
+            Deferred::from_steps(zip, vec![Step::new(Zip::advance)])
+        } else {
+            Deferred::from_steps(zip, vec![])
+        }
+    }
+}
+
+/// State of two deferred executions resumed in lockstep, produced by `Deferred::zip()`.
+pub struct Zip<S, T> {
+    left: Deferred<S>,
+    right: Deferred<T>,
+}
+
+impl<S: 'static, T: 'static> Zip<S, T> {
+    /// Gets first deferred execution.
+    #[inline]
+    pub fn left(&self) -> &Deferred<S> {
+        &self.left
+    }
+
+    /// Gets second deferred execution.
+    #[inline]
+    pub fn right(&self) -> &Deferred<T> {
+        &self.right
+    }
+
+    /// Gets current states of both deferred executions.
+    #[inline]
+    pub fn states(&self) -> (Option<&S>, Option<&T>) {
+        (self.left.state(), self.right.state())
+    }
+
+    /// Consumes zip and returns both deferred executions.
+    pub fn into_inner(self) -> (Deferred<S>, Deferred<T>) {
+        (self.left, self.right)
+    }
+
+    /// Consumes zip and returns final states of both deferred executions, executing their
+    /// remaining parts if there are any.
+    pub fn into_states(self) -> (S, T) {
+        (self.left.consume(), self.right.consume())
+    }
+
+    /// Resumes both executions and yields with their state, asking to be executed again as long
+    /// as any of them can be resumed.
+    fn advance(context: Context<Self>) -> Context<Self> {
+        let mut zip = context.state();
+        zip.left.step();
+        zip.right.step();
+        if zip.left.can_resume() || zip.right.can_resume() {
+            Context::from_state(zip).again()
+        } else {
+            Context::from_state(zip)
+        }
     }
 }
@@ -0,0 +1,164 @@
+use crate::context::*;
+use crate::deferred::*;
+use alloc::vec::Vec;
+
+/// Builder used to construct deferred execution incrementally, for example from list of operations
+/// known only at runtime.
+///
+/// # Example
+/// ```
+/// # #[macro_use] extern crate deferred;
+/// # use deferred::*;
+/// # fn main() {
+/// fn double(c: Context<i32>) -> Context<i32> {
+///     state!(c.state() * 2)
+/// }
+///
+/// fn negate(c: Context<i32>) -> Context<i32> {
+///     state!(-c.state())
+/// }
+///
+/// fn sub(v: i32) -> Deferred<i32> {
+///     deferred!(v, [|c| state!(c.state() - 3)])
+/// }
+///
+/// let n = 10;
+/// let d = DeferredBuilder::new(1)
+///     .then_part(|c| state!(c.state() + 1))
+///     .then(move |c| state!(c.state() + n))
+///     .then_sub(sub)
+///     .extend(vec![double as Part<i32>, double])
+///     .prepend_part(|c| state!(c.state() * 100))
+///     .insert_at(1, Step::new(negate).named("negate"))
+///     .build();
+/// assert_eq!(d.consume(), (-100 + 1 + 10 - 3) * 4);
+/// # }
+/// ```
+pub struct DeferredBuilder<S> {
+    state: S,
+    steps: Vec<Step<S>>,
+}
+
+impl<S> DeferredBuilder<S> {
+    /// Creates new deferred execution builder.
+    ///
+    /// # Arguments
+    /// * `state` - context initial state.
+    pub fn new(state: S) -> Self {
+        Self {
+            state,
+            steps: Vec::new(),
+        }
+    }
+
+    /// Gets number of steps added so far.
+    #[inline]
+    pub fn len(&self) -> usize {
+        self.steps.len()
+    }
+
+    /// Tells if there are no steps added yet.
+    #[inline]
+    pub fn is_empty(&self) -> bool {
+        self.steps.is_empty()
+    }
+
+    /// Appends closure, which gets boxed. Use `then_part()` for logic part that captures nothing.
+    ///
+    /// # Arguments
+    /// * `part` - closure.
+    pub fn then<F>(self, part: F) -> Self
+    where
+        F: FnOnce(Context<S>) -> Context<S> + Send + 'static,
+    {
+        self.then_step(Step::closure(part))
+    }
+
+    /// Appends logic part.
+    ///
+    /// # Arguments
+    /// * `part` - logic part.
+    pub fn then_part(self, part: Part<S>) -> Self {
+        self.then_step(Step::new(part))
+    }
+
+    /// Appends step.
+    ///
+    /// # Arguments
+    /// * `step` - step.
+    pub fn then_step(mut self, step: Step<S>) -> Self {
+        self.steps.push(step);
+        self
+    }
+
+    /// Appends step that executes subroutine created from current state.
+    ///
+    /// # Arguments
+    /// * `factory` - function that creates subroutine from current state.
+    pub fn then_sub<F>(self, factory: F) -> Self
+    where
+        F: FnOnce(S) -> Deferred<S> + Send + 'static,
+    {
+        self.then(move |c| factory(c.state()).into())
+    }
+
+    /// Appends all steps from iterator.
+    ///
+    /// # Arguments
+    /// * `steps` - iterator of steps or logic parts.
+    pub fn extend<I, T>(mut self, steps: I) -> Self
+    where
+        I: IntoIterator<Item = T>,
+        T: Into<Step<S>>,
+    {
+        self.steps.extend(steps.into_iter().map(Into::into));
+        self
+    }
+
+    /// Prepends closure, which gets boxed. Use `prepend_part()` for logic part that captures
+    /// nothing.
+    ///
+    /// # Arguments
+    /// * `part` - closure.
+    pub fn prepend<F>(self, part: F) -> Self
+    where
+        F: FnOnce(Context<S>) -> Context<S> + Send + 'static,
+    {
+        self.insert_at(0, Step::closure(part))
+    }
+
+    /// Prepends logic part.
+    ///
+    /// # Arguments
+    /// * `part` - logic part.
+    pub fn prepend_part(self, part: Part<S>) -> Self {
+        self.insert_at(0, Step::new(part))
+    }
+
+    /// Inserts step at given position.
+    ///
+    /// # Arguments
+    /// * `index` - position of inserted step.
+    /// * `step` - step or logic part.
+    ///
+    /// # Panics
+    /// * when `index` is greater than number of steps.
+    pub fn insert_at<T>(mut self, index: usize, step: T) -> Self
+    where
+        T: Into<Step<S>>,
+    {
+        self.steps.insert(index, step.into());
+        self
+    }
+
+    /// Consumes builder and returns deferred execution.
+    pub fn build(self) -> Deferred<S> {
+        Deferred::from_steps(self.state, self.steps)
+    }
+}
+
+impl<S> From<DeferredBuilder<S>> for Deferred<S> {
+    fn from(builder: DeferredBuilder<S>) -> Self {
+        builder.build()
+    }
+}
@@ -1,13 +1,117 @@
+use crate::context::{Event, Host};
 use crate::deferred::*;
-use std::collections::HashMap;
+use crate::join_handle::{JoinHandle, Outcome};
+use crate::limits::*;
+use crate::resources::Resources;
+use crate::spawner::Spawner;
+use crate::value::{SendValue, Value};
+#[cfg(not(feature = "std"))]
+use alloc::collections::{BTreeMap as Map, BTreeSet as Set};
+use alloc::{boxed::Box, collections::BTreeMap, string::String, vec, vec::Vec};
+use core::{
+    cell::RefCell,
+    fmt,
+    iter::FromIterator,
+    ops::{Deref, DerefMut},
+};
+#[cfg(feature = "std")]
+use std::collections::{HashMap as Map, HashSet as Set};
 
 /// Alias for deferred execution identifier;
 pub type Id = usize;
 
+/// Alias for point in time measured by `DeferredManager`, in units chosen by host (frames,
+/// milliseconds and so on).
+pub type Tick = u64;
+
+/// Message of failure of unit that has lost its state to panic of its part.
+const LOST_STATE: &str = "Deferred execution has lost its state";
+
+/// Unit waiting in timer queue.
+enum Timer<S> {
+    Once(Deferred<S>),
+    Every {
+        interval: Tick,
+        factory: Box<dyn FnMut() -> Deferred<S> + Send>,
+    },
+}
+
+/// Reason why deferred execution unit was moved to failed set of `DeferredManager`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Failure {
+    /// One of its parts has panicked with given message.
+    Panic(String),
+    /// It has exceeded one of its limits.
+    Limit(LimitExceeded),
+}
+
+impl fmt::Display for Failure {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Failure::Panic(message) => write!(f, "Part has panicked: {}", message),
+            Failure::Limit(exceeded) => write!(f, "Limit exceeded: {}", exceeded),
+        }
+    }
+}
+
 /// Deferred execution manager used to store and resume.
+///
+/// Without `std` feature units are stored in ordered map instead of hash map, and manager is not
+/// `Send` as state it shares with handles is not guarded by mutex.
+///
+/// # Timers
+/// Manager measures time in ticks advanced by host with `advance_time()` or `set_time()`. Units
+/// scheduled with `run_at()`, `run_after_delay()` and `run_every()` wait in timer queue ordered by
+/// time and get registered when their time comes.
+///
+/// # Events
+/// Unit which part returns context with `wait_for()` falls asleep until that event gets signaled
+/// with `signal()` - passes over all units skip sleeping ones entirely.
+///
+/// # Spawning
+/// Parts can start new units with spawner got from `Context::spawner()`. They get registered
+/// right after the operation that has executed these parts returns, so `resume_all()` does not
+/// resume them in the same pass.
+///
+/// # Scopes
+/// Units can be registered as children of other unit with `run_child()` or
+/// `Context::run_child()`. Cancelling or failing unit cancels all its descendants and calls
+/// their cleanup set with `Deferred::on_cancel()`, while unit that has executed all its parts is
+/// kept joining (it is not resumed anymore) until its attached children finish or get detached
+/// with `detach()`.
+///
+/// # Handles
+/// Units registered with `spawn()` are owned by returned `JoinHandle`, which gets their final
+/// state. When all clones of handle are dropped, `resume_all()` cancels the unit unless it was
+/// detached.
+///
+/// # Resources
+/// Manager owns typed resources got with `resources()` and passes them to parts of every unit,
+/// so units can share services without storing them in their state.
+///
+/// # Panic isolation
+/// With `std` feature you can enable isolation of panics with `set_isolate_panics()` - unit which
+/// part has panicked is then moved to failed set together with panic message, while all other
+/// units are kept and keep running. State of failed unit is lost together with panicking part,
+/// so its own cleanup set with `Deferred::on_cancel()` is not called (cleanup of its descendants
+/// still is).
 pub struct DeferredManager<S> {
-    registry: HashMap<Id, Deferred<S>>,
-    id_generator: Id,
+    registry: Map<Id, Deferred<S>>,
+    waits: RefCell<Waits>,
+    timers: BTreeMap<(Tick, Id), Timer<S>>,
+    scheduled: Map<Id, Tick>,
+    time: Tick,
+    failed: Map<Id, Failure>,
+    spawner: Spawner<S>,
+    resources: Resources,
+    parents: Map<Id, Id>,
+    children: Map<Id, Vec<Id>>,
+    joining: Set<Id>,
+    joins: Map<Id, JoinHandle<S>>,
+    isolate_panics: bool,
+    default_limits: Option<Limits>,
+    clock: Option<Clock>,
+    emitted: Map<Id, Vec<SendValue>>,
 }
 
 impl<S> DeferredManager<S> {
@@ -54,6 +158,190 @@ impl<S> DeferredManager<S> {
         self.registry.len()
     }
 
+    /// Tells if panics of parts are isolated.
+    #[inline]
+    pub fn isolates_panics(&self) -> bool {
+        self.isolate_panics
+    }
+
+    /// Enables or disables isolation of panics of parts. When enabled, unit which part has
+    /// panicked is moved to failed set instead of unwinding through the manager.
+    ///
+    /// # Arguments
+    /// * `isolate` - tells if panics should be isolated.
+    ///
+    /// # Example
+    /// ```
+    /// # #[macro_use] extern crate deferred;
+    /// # use deferred::*;
+    /// # fn main() {
+    /// # std::panic::set_hook(Box::new(|_| {}));
+    /// let mut manager = DeferredManager::new();
+    /// manager.set_isolate_panics(true);
+    /// let a = manager.run(deferred!(1, [|c| -> Context<i32> { panic!("oops") }]));
+    /// let b = manager.run(deferred!(2, [|c| state!(c.state() + 1)]));
+    /// manager.resume_all();
+    /// assert_eq!(manager.failure(a), Some(&Failure::Panic("oops".to_owned())));
+    /// assert_eq!(manager.has(a), false);
+    /// assert_eq!(manager.failed_count(), 1);
+    /// assert_eq!(manager.take_failures(), vec![(a, Failure::Panic("oops".to_owned()))]);
+    /// assert_eq!(manager.failed_count(), 0);
+    /// # }
+    /// ```
+    #[cfg(feature = "std")]
+    pub fn set_isolate_panics(&mut self, isolate: bool) {
+        self.isolate_panics = isolate;
+    }
+
+    /// Gets limits applied to units registered without their own limits.
+    #[inline]
+    pub fn default_limits(&self) -> Option<&Limits> {
+        self.default_limits.as_ref()
+    }
+
+    /// Sets limits applied to units registered from now on without their own limits. Unit that
+    /// exceeds its limits is moved to failed set.
+    ///
+    /// # Arguments
+    /// * `limits` - default limits or `None` to not limit units.
+    ///
+    /// # Example
+    /// ```
+    /// # #[macro_use] extern crate deferred;
+    /// # use deferred::*;
+    /// # fn main() {
+    /// fn forever(c: Context<i32>) -> Context<i32> {
+    ///     deferred!(c.state(), [forever]).into()
+    /// }
+    ///
+    /// let mut manager = DeferredManager::new();
+    /// manager.set_default_limits(Some(Limits {
+    ///     max_steps: Some(100),
+    ///     ..Default::default()
+    /// }));
+    /// let id = manager.run(deferred!(0, [forever]));
+    /// manager.resume_all();
+    /// assert_eq!(manager.has(id), false);
+    /// assert_eq!(
+    ///     manager.failure(id).unwrap().to_string(),
+    ///     "Limit exceeded: Executed more than 100 parts",
+    /// );
+    /// # }
+    /// ```
+    pub fn set_default_limits(&mut self, limits: Option<Limits>) {
+        self.default_limits = limits;
+    }
+
+    /// Gets clock given to units registered without their own clock.
+    #[inline]
+    pub fn clock(&self) -> Option<Clock> {
+        self.clock
+    }
+
+    /// Sets clock given to limited units registered from now on without their own clock, to
+    /// measure their parts against `Limits::max_duration`. Host on target without `std` clock
+    /// (like `wasm32-unknown-unknown`) supplies its time source this way.
+    ///
+    /// # Arguments
+    /// * `clock` - clock giving time passed since any fixed moment or `None` to use default one.
+    ///
+    /// # Example
+    /// ```
+    /// # #[macro_use] extern crate deferred;
+    /// # use deferred::*;
+    /// # use std::time::Duration;
+    /// # fn main() {
+    /// fn forever(c: Context<i32>) -> Context<i32> {
+    ///     deferred!(c.state(), [forever]).into()
+    /// }
+    ///
+    /// let mut manager = DeferredManager::new();
+    /// manager.set_clock(Some(|| Duration::from_secs(1)));
+    /// manager.set_default_limits(Some(Limits {
+    ///     max_steps: Some(100),
+    ///     max_duration: Some(Duration::from_millis(10)),
+    ///     ..Default::default()
+    /// }));
+    /// let id = manager.run(deferred!(0, [forever]));
+    /// manager.resume_all();
+    /// assert_eq!(
+    ///     manager.failure(id).unwrap().to_string(),
+    ///     "Limit exceeded: Executed more than 100 parts",
+    /// );
+    /// # }
+    /// ```
+    pub fn set_clock(&mut self, clock: Option<Clock>) {
+        self.clock = clock;
+    }
+
+    /// Takes items emitted so far by deferred execution unit with given id, also after it has
+    /// completed. Items of unit that got cancelled or failed are dropped together with it.
+    ///
+    /// # Arguments
+    /// * `id` - deferred execution id (got from calling `run()` method).
+    ///
+    /// # Example
+    /// ```
+    /// # #[macro_use] extern crate deferred;
+    /// # use deferred::*;
+    /// # fn main() {
+    /// let mut manager = DeferredManager::new();
+    /// let id = manager.run(deferred!(0, [
+    ///     |c| state!(c.state() + 1).emit(1),
+    ///     |c| state!(c.state() + 1).emit(2).emit(3)
+    /// ]));
+    /// manager.resume_all();
+    /// let items = manager.drain_emitted(id);
+    /// assert_eq!(items.len(), 1);
+    /// assert_eq!(items[0].get::<i32>(), Some(&1));
+    /// manager.resume_all();
+    /// assert_eq!(manager.has(id), false);
+    /// assert_eq!(manager.drain_emitted(id).len(), 2);
+    /// assert!(manager.drain_emitted(id).is_empty());
+    /// # }
+    /// ```
+    pub fn drain_emitted(&mut self, id: Id) -> Vec<Value> {
+        self.emitted
+            .remove(&id)
+            .map(|items| items.into_iter().map(Value::from).collect())
+            .unwrap_or_default()
+    }
+
+    /// Takes items emitted so far by all deferred execution units and returns them as vector of
+    /// id-items pairs sorted by id.
+    pub fn drain_all_emitted(&mut self) -> Vec<(Id, Vec<Value>)> {
+        let mut result = core::mem::take(&mut self.emitted)
+            .into_iter()
+            .map(|(id, items)| (id, items.into_iter().map(Value::from).collect()))
+            .collect::<Vec<_>>();
+        result.sort_by_key(|(id, _)| *id);
+        result
+    }
+
+    /// Gets number of failed deferred executions.
+    #[inline]
+    pub fn failed_count(&self) -> usize {
+        self.failed.len()
+    }
+
+    /// Gets reason of failure of deferred execution unit with given id, if it has failed.
+    ///
+    /// # Arguments
+    /// * `id` - deferred execution id (got from calling `run()` method).
+    #[inline]
+    pub fn failure(&self, id: Id) -> Option<&Failure> {
+        self.failed.get(&id)
+    }
+
+    /// Removes all failures and returns them as vector of id-failure pairs sorted by id.
+    pub fn take_failures(&mut self) -> Vec<(Id, Failure)> {
+        let mut result = core::mem::take(&mut self.failed)
+            .into_iter()
+            .collect::<Vec<_>>();
+        result.sort_by_key(|(id, _)| *id);
+        result
+    }
+
     /// Register deferred logic for later execution.
     ///
     /// # Arguments
@@ -90,9 +378,208 @@ impl<S> DeferredManager<S> {
     /// # }
     /// ```
     pub fn run(&mut self, deferred: Deferred<S>) -> Id {
-        let id = self.id_generator;
-        self.id_generator += 1;
-        self.registry.insert(id, deferred);
+        let id = self.generate_id();
+        self.insert(id, deferred);
+        id
+    }
+
+    /// Registers deferred logic and returns handle that owns it, used to wait for its completion
+    /// and take its final state. Unit gets cancelled by next operation that resumes units or moves
+    /// time after all clones of handle are dropped, unless handle was detached.
+    ///
+    /// # Arguments
+    /// * `deferred` - deferred execution unit.
+    ///
+    /// # Example
+    /// ```
+    /// # #[macro_use] extern crate deferred;
+    /// # use deferred::*;
+    /// # fn main() {
+    /// let mut manager = DeferredManager::new();
+    /// let handle = manager.spawn(deferred!(20, [
+    ///     |c| state!(c.state() + 1),
+    ///     |c| state!(c.state() * 2)
+    /// ]));
+    /// let id = handle.id();
+    /// handle.detach();
+    /// manager.resume_all();
+    /// assert_eq!(manager.consume(id), Some(42));
+    /// # }
+    /// ```
+    pub fn spawn(&mut self, deferred: Deferred<S>) -> JoinHandle<S> {
+        let id = self.run(deferred);
+        let handle = JoinHandle::new(id);
+        self.joins.insert(id, handle.clone());
+        handle
+    }
+
+    /// Gets current time.
+    #[inline]
+    pub fn time(&self) -> Tick {
+        self.time
+    }
+
+    /// Sets current time and registers units which time has come. Time cannot go back, so
+    /// earlier time is ignored.
+    ///
+    /// # Arguments
+    /// * `time` - current time.
+    pub fn set_time(&mut self, time: Tick) {
+        self.sweep_handles();
+        self.time = self.time.max(time);
+        while let Some((&(tick, id), _)) = self.timers.iter().next() {
+            if tick > self.time {
+                break;
+            }
+            let timer = self.timers.remove(&(tick, id)).unwrap();
+            self.scheduled.remove(&id);
+            match timer {
+                Timer::Once(deferred) => self.insert(id, deferred),
+                Timer::Every {
+                    interval,
+                    mut factory,
+                } => {
+                    let deferred = factory();
+                    let unit = self.generate_id();
+                    self.insert(unit, deferred);
+                    let next = ((self.time - tick) / interval)
+                        .checked_add(1)
+                        .and_then(|runs| runs.checked_mul(interval))
+                        .and_then(|delay| tick.checked_add(delay));
+                    if let Some(next) = next {
+                        self.schedule(id, next, Timer::Every { interval, factory });
+                    }
+                }
+            }
+        }
+    }
+
+    /// Moves current time forward and registers units which time has come.
+    ///
+    /// # Arguments
+    /// * `delta` - time that has passed.
+    #[inline]
+    pub fn advance_time(&mut self, delta: Tick) {
+        self.set_time(self.time.saturating_add(delta));
+    }
+
+    /// Gets resources shared by all units.
+    #[inline]
+    pub fn resources(&self) -> &Resources {
+        &self.resources
+    }
+
+    /// Gets time of the earliest scheduled unit, so host knows when manager needs to be ticked
+    /// next (units that are runnable already need resuming regardless of that).
+    #[inline]
+    pub fn next_wake_time(&self) -> Option<Tick> {
+        self.timers.keys().next().map(|(tick, _)| *tick)
+    }
+
+    /// Gets number of units waiting in timer queue.
+    #[inline]
+    pub fn scheduled_count(&self) -> usize {
+        self.timers.len()
+    }
+
+    /// Tells if unit or recurring job with given id waits in timer queue.
+    ///
+    /// # Arguments
+    /// * `id` - deferred execution id (got from calling `run_at()` or similar method).
+    #[inline]
+    pub fn is_scheduled(&self, id: Id) -> bool {
+        self.scheduled.contains_key(&id)
+    }
+
+    /// Schedules deferred logic to be registered at given time. Returned id is the id unit gets
+    /// when registered.
+    ///
+    /// # Arguments
+    /// * `time` - time of registration.
+    /// * `deferred` - deferred execution unit.
+    ///
+    /// # Example
+    /// ```
+    /// # #[macro_use] extern crate deferred;
+    /// # use deferred::*;
+    /// # fn main() {
+    /// let mut manager = DeferredManager::new();
+    /// let id = manager.run_at(10, deferred!(0, [|c| state!(c.state() + 1)]));
+    /// let id2 = manager.run_after_delay(5, deferred!(0, [|c| state!(c.state() + 1)]));
+    /// assert_eq!(manager.next_wake_time(), Some(5));
+    /// manager.advance_time(5);
+    /// assert_eq!(manager.state(id2), Some(&0));
+    /// assert!(manager.is_scheduled(id));
+    /// manager.advance_time(5);
+    /// assert_eq!(manager.state(id), Some(&0));
+    /// assert_eq!(manager.next_wake_time(), None);
+    /// # }
+    /// ```
+    pub fn run_at(&mut self, time: Tick, deferred: Deferred<S>) -> Id {
+        let id = self.generate_id();
+        if time <= self.time {
+            self.insert(id, deferred);
+        } else {
+            self.schedule(id, time, Timer::Once(deferred));
+        }
+        id
+    }
+
+    /// Schedules deferred logic to be registered after given time passes.
+    ///
+    /// # Arguments
+    /// * `delay` - time to wait before registration.
+    /// * `deferred` - deferred execution unit.
+    #[inline]
+    pub fn run_after_delay(&mut self, delay: Tick, deferred: Deferred<S>) -> Id {
+        self.run_at(self.time.saturating_add(delay), deferred)
+    }
+
+    /// Schedules recurring job that registers new unit created by factory every time interval
+    /// passes, first one after single interval. When time moves by more than one interval at
+    /// once, runs that were missed get coalesced into single unit and job continues with the
+    /// next run after current time. Returned id identifies the job and
+    /// can be used to cancel it, while registered units get ids of their own. Job ends when its
+    /// next run would be past the maximal time.
+    ///
+    /// # Arguments
+    /// * `interval` - time between registrations.
+    /// * `factory` - function that creates units.
+    ///
+    /// # Panics
+    /// * when `interval` is zero.
+    ///
+    /// # Example
+    /// ```
+    /// # #[macro_use] extern crate deferred;
+    /// # use deferred::*;
+    /// # fn main() {
+    /// let mut manager = DeferredManager::new();
+    /// let job = manager.run_every(10, || deferred!("autosave", [|c| state!(c.state())]));
+    /// manager.advance_time(25);
+    /// assert_eq!(manager.count(), 1);
+    /// assert_eq!(manager.next_wake_time(), Some(30));
+    /// manager.advance_time(5);
+    /// assert_eq!(manager.count(), 2);
+    /// assert!(manager.cancel(job));
+    /// manager.advance_time(100);
+    /// assert_eq!(manager.count(), 2);
+    /// # }
+    /// ```
+    pub fn run_every<F>(&mut self, interval: Tick, factory: F) -> Id
+    where
+        F: FnMut() -> Deferred<S> + Send + 'static,
+    {
+        assert!(
+            interval > 0,
+            "Trying to run recurring job with zero interval"
+        );
+        let id = self.generate_id();
+        let timer = Timer::Every {
+            interval,
+            factory: Box::new(factory),
+        };
+        self.schedule(id, self.time.saturating_add(interval), timer);
         id
     }
 
@@ -133,10 +620,232 @@ impl<S> DeferredManager<S> {
     /// ```
     #[inline]
     pub fn cancel(&mut self, id: Id) -> bool {
-        self.registry.remove(&id).is_some()
+        if let Some(time) = self.scheduled.remove(&id) {
+            return self.timers.remove(&(time, id)).is_some();
+        }
+        self.cancel_tree(id)
+    }
+
+    /// Registers deferred logic as child of unit with given id and returns its id, or `None` if
+    /// there is no such parent.
+    ///
+    /// # Arguments
+    /// * `parent` - id of parent unit.
+    /// * `deferred` - deferred execution unit.
+    ///
+    /// # Example
+    /// ```
+    /// # #[macro_use] extern crate deferred;
+    /// # use deferred::*;
+    /// # fn main() {
+    /// use std::sync::Arc;
+    /// use std::sync::atomic::{AtomicI32, Ordering};
+    ///
+    /// let cleaned = Arc::new(AtomicI32::new(0));
+    /// let mut manager = DeferredManager::new();
+    /// let parent = manager.run(deferred!(0, [|c| state!(c.state() + 1)]));
+    /// let child = manager.run_child(parent, deferred!(0, [
+    ///     |c| state!(c.state() + 1),
+    ///     |c| state!(c.state() + 1)
+    /// ])).unwrap();
+    /// let c = cleaned.clone();
+    /// let grandchild = manager
+    ///     .run_child(child, deferred!(5, [|c| state!(c.state())]).on_cancel(move |v| c.store(v, Ordering::Relaxed)))
+    ///     .unwrap();
+    /// assert_eq!(manager.children(parent), &[child]);
+    /// assert_eq!(manager.parent(grandchild), Some(child));
+    ///
+    /// manager.resume(parent);
+    /// assert!(manager.is_joining(parent));
+    /// assert_eq!(manager.state(parent), Some(&1));
+    /// assert!(manager.cancel(child));
+    /// assert!(!manager.has(grandchild));
+    /// assert_eq!(cleaned.load(Ordering::Relaxed), 5);
+    /// assert!(!manager.has(parent));
+    /// # }
+    /// ```
+    pub fn run_child(&mut self, parent: Id, deferred: Deferred<S>) -> Option<Id> {
+        if !self.registry.contains_key(&parent) {
+            return None;
+        }
+        let id = self.generate_id();
+        self.insert(id, deferred);
+        self.link(parent, id);
+        Some(id)
+    }
+
+    /// Detaches unit from its parent, so it is not cancelled together with parent anymore and
+    /// parent does not wait for it. Returns `false` if unit has no parent.
+    ///
+    /// # Arguments
+    /// * `id` - deferred execution id.
+    pub fn detach(&mut self, id: Id) -> bool {
+        match self.parents.remove(&id) {
+            Some(parent) => {
+                self.unlink(parent, id);
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Gets id of parent of unit, if it has one.
+    ///
+    /// # Arguments
+    /// * `id` - deferred execution id.
+    #[inline]
+    pub fn parent(&self, id: Id) -> Option<Id> {
+        self.parents.get(&id).cloned()
+    }
+
+    /// Gets ids of attached children of unit.
+    ///
+    /// # Arguments
+    /// * `id` - deferred execution id.
+    #[inline]
+    pub fn children(&self, id: Id) -> &[Id] {
+        self.children.get(&id).map_or(&[], |children| children)
+    }
+
+    /// Tells if unit has executed all its parts and waits for its attached children to finish.
+    ///
+    /// # Arguments
+    /// * `id` - deferred execution id.
+    #[inline]
+    pub fn is_joining(&self, id: Id) -> bool {
+        self.joining.contains(&id)
+    }
+
+    /// Gets deferred execution unit by its id.
+    ///
+    /// # Arguments
+    /// * `id` - deferred execution id (got from calling `run()` method).
+    #[inline]
+    pub fn get(&self, id: Id) -> Option<&Deferred<S>> {
+        self.registry.get(&id)
+    }
+
+    /// Gets mutable deferred execution unit by its id. Returned guard updates whether unit sleeps
+    /// when dropped, so unit can be signaled or resumed in place through it.
+    ///
+    /// # Arguments
+    /// * `id` - deferred execution id (got from calling `run()` method).
+    pub fn get_mut(&mut self, id: Id) -> Option<UnitMut<'_, S>> {
+        let deferred = self.registry.get_mut(&id)?;
+        Some(UnitMut::new(id, deferred, &self.waits, &self.joining))
+    }
+
+    /// Gets current state of deferred execution unit by its id.
+    ///
+    /// # Arguments
+    /// * `id` - deferred execution id (got from calling `run()` method).
+    ///
+    /// # Example
+    /// ```
+    /// # #[macro_use] extern crate deferred;
+    /// # use deferred::*;
+    /// # fn main() {
+    /// let mut manager = DeferredManager::new();
+    /// let id = manager.run(deferred!(1, [
+    ///     |c| state!(c.state() + 1),
+    ///     |c| state!(c.state() * 2)
+    /// ]));
+    /// manager.resume(id);
+    /// assert_eq!(manager.state(id), Some(&2));
+    /// *manager.state_mut(id).unwrap() = 10;
+    /// assert_eq!(manager.consume(id), Some(20));
+    /// assert_eq!(manager.state(id), None);
+    /// # }
+    /// ```
+    #[inline]
+    pub fn state(&self, id: Id) -> Option<&S> {
+        self.registry.get(&id).and_then(|deferred| deferred.state())
+    }
+
+    /// Gets mutable current state of deferred execution unit by its id.
+    ///
+    /// # Arguments
+    /// * `id` - deferred execution id (got from calling `run()` method).
+    #[inline]
+    pub fn state_mut(&mut self, id: Id) -> Option<&mut S> {
+        self.registry
+            .get_mut(&id)
+            .and_then(|deferred| deferred.state_mut())
+    }
+
+    /// Gets iterator over ids of deferred execution units, in no particular order.
+    pub fn ids(&self) -> impl Iterator<Item = Id> + '_ {
+        self.registry.keys().cloned()
+    }
+
+    /// Gets iterator over deferred execution units with their ids, in no particular order.
+    ///
+    /// # Example
+    /// ```
+    /// # #[macro_use] extern crate deferred;
+    /// # use deferred::*;
+    /// # fn main() {
+    /// let mut manager = (0..4)
+    ///     .map(|v| deferred!(v, [|c| state!(c.state() * 10)]))
+    ///     .collect::<DeferredManager<i32>>();
+    /// manager.retain(|_, d| d.state() != Some(&2));
+    /// for (_, mut d) in manager.iter_mut() {
+    ///     *d.state_mut().unwrap() += 1;
+    /// }
+    /// let mut states = manager
+    ///     .iter()
+    ///     .map(|(_, d)| *d.state().unwrap())
+    ///     .collect::<Vec<_>>();
+    /// states.sort();
+    /// assert_eq!(states, vec![1, 2, 4]);
+    /// let mut ids = manager.ids().collect::<Vec<_>>();
+    /// ids.sort();
+    /// assert_eq!(ids, vec![0, 1, 3]);
+    /// # }
+    /// ```
+    pub fn iter(&self) -> impl Iterator<Item = (Id, &Deferred<S>)> {
+        self.registry.iter().map(|(id, deferred)| (*id, deferred))
+    }
+
+    /// Gets iterator over mutable deferred execution units with their ids, in no particular
+    /// order. Every unit is given behind guard that updates whether it sleeps when dropped.
+    pub fn iter_mut(&mut self) -> impl Iterator<Item = (Id, UnitMut<'_, S>)> {
+        let waits = &self.waits;
+        let joining = &self.joining;
+        self.registry
+            .iter_mut()
+            .map(move |(id, deferred)| (*id, UnitMut::new(*id, deferred, waits, joining)))
+    }
+
+    /// Keeps only deferred execution units for which predicate returns `true`, cancelling the
+    /// other ones.
+    ///
+    /// # Arguments
+    /// * `predicate` - tells if unit with given id should be kept.
+    pub fn retain<F>(&mut self, mut predicate: F)
+    where
+        F: FnMut(Id, &mut Deferred<S>) -> bool,
+    {
+        let waits = &self.waits;
+        let joining = &self.joining;
+        let ids = self
+            .registry
+            .iter_mut()
+            .filter_map(|(id, deferred)| {
+                if predicate(*id, &mut UnitMut::new(*id, deferred, waits, joining)) {
+                    None
+                } else {
+                    Some(*id)
+                }
+            })
+            .collect::<Vec<_>>();
+        for id in ids {
+            self.cancel_tree(id);
+        }
     }
 
-    /// Resume specified deferred execution unit by its id.
+    /// Resume specified deferred execution unit by its id. Resuming sleeping unit this way wakes
+    /// it.
     ///
     /// # Arguments
     /// * `id` - deferred execution id (got from calling `run()` method).
@@ -173,18 +882,101 @@ impl<S> DeferredManager<S> {
     /// ```
     #[inline]
     pub fn resume(&mut self, id: Id) -> bool {
-        if let Some(deferred) = self.registry.remove(&id) {
-            if let Some(deferred) = deferred.resume() {
-                if deferred.can_resume() {
-                    self.registry.insert(id, deferred);
-                }
-                true
-            } else {
-                false
-            }
-        } else {
-            false
-        }
+        self.resume_with_status(id, |deferred, host| {
+            deferred.resume_hosted(None, Some(host))
+        })
+    }
+
+    /// Resume specified deferred execution unit by its id, passing given input to its next
+    /// executed part (which belongs to its innermost running subroutine), the same way as
+    /// `Deferred::resume_with()` does.
+    ///
+    /// # Arguments
+    /// * `id` - deferred execution id (got from calling `run()` method).
+    /// * `input` - input for the next part.
+    ///
+    /// # Example
+    /// ```
+    /// # #[macro_use] extern crate deferred;
+    /// # use deferred::*;
+    /// # fn main() {
+    /// let mut manager = DeferredManager::new();
+    /// let id = manager.run(deferred!(0, [
+    ///     |c| {
+    ///         let v = *c.input::<i32>().unwrap();
+    ///         state!(c.state() + v)
+    ///     },
+    ///     |c| state!(c.state() * 2)
+    /// ]));
+    /// assert!(manager.resume_with(id, 21));
+    /// assert_eq!(manager.consume(id), Some(42));
+    /// # }
+    /// ```
+    pub fn resume_with<I: 'static>(&mut self, id: Id, input: I) -> bool {
+        self.resume_with_status(id, |deferred, host| {
+            deferred.resume_hosted(Some(Value::from_box(Box::new(input))), Some(host))
+        })
+    }
+
+    /// Resume specified deferred execution unit at most `n` times and return number of resumes
+    /// done. Unit is removed when it completes, or when it fails, in which case resumes done
+    /// before failure are counted.
+    ///
+    /// # Arguments
+    /// * `id` - deferred execution id (got from calling `run()` method).
+    /// * `n` - maximal number of resumes.
+    ///
+    /// # Example
+    /// ```
+    /// # #[macro_use] extern crate deferred;
+    /// # use deferred::*;
+    /// # fn main() {
+    /// let mut manager = DeferredManager::new();
+    /// let id = manager.run(deferred!(0, [
+    ///     |c| state!(c.state() + 1),
+    ///     |c| state!(c.state() + 1),
+    ///     |c| state!(c.state() + 1)
+    /// ]));
+    /// assert_eq!(manager.resume_n(id, 2), 2);
+    /// assert_eq!(manager.has(id), true);
+    /// assert_eq!(manager.resume_n(id, 2), 1);
+    /// assert_eq!(manager.has(id), false);
+    /// # }
+    /// ```
+    pub fn resume_n(&mut self, id: Id, n: usize) -> usize {
+        self.resume_steps(id, |deferred, host, steps| {
+            deferred.step_n(n, Some(host), steps)
+        })
+    }
+
+    /// Resume specified deferred execution unit as long as predicate holds for its current state
+    /// and return number of resumes done. Unit is removed when it completes.
+    ///
+    /// # Arguments
+    /// * `id` - deferred execution id (got from calling `run()` method).
+    /// * `predicate` - tells if unit should be resumed with given state.
+    pub fn resume_while<F>(&mut self, id: Id, predicate: F) -> usize
+    where
+        F: FnMut(&S) -> bool,
+    {
+        self.resume_steps(id, |deferred, host, steps| {
+            deferred.step_while(predicate, Some(host), steps)
+        })
+    }
+
+    /// Resume specified deferred execution unit until it produces state that satisfies predicate
+    /// and return number of resumes done. Unit is removed when it completes.
+    ///
+    /// # Arguments
+    /// * `id` - deferred execution id (got from calling `run()` method).
+    /// * `predicate` - tells if produced state is the one we wait for.
+    pub fn resume_until_state<F>(&mut self, id: Id, predicate: F) -> usize
+    where
+        F: FnMut(&S) -> bool,
+    {
+        self.resume_steps(id, |deferred, host, steps| {
+            deferred.step_until_state(predicate, Some(host), steps)
+        })
     }
 
     /// Consume specified deferred execution unit by its id and return its state.
@@ -224,10 +1016,58 @@ impl<S> DeferredManager<S> {
     /// ```
     #[inline]
     pub fn consume(&mut self, id: Id) -> Option<S> {
-        if let Some(deferred) = self.registry.remove(&id) {
-            Some(deferred.consume())
-        } else {
-            None
+        self.sweep_handles();
+        let mut finished = vec![];
+        let mut stack = vec![id];
+        while let Some(unit) = stack.pop() {
+            if !self.run_to_end(unit) {
+                continue;
+            }
+            finished.push(unit);
+            let children = self.children.remove(&unit).unwrap_or_default();
+            for child in children.iter().rev() {
+                self.parents.remove(child);
+                stack.push(*child);
+            }
+        }
+        let mut result = None;
+        for unit in finished.into_iter().rev() {
+            self.joining.remove(&unit);
+            if let Some(deferred) = self.remove(unit) {
+                self.release(unit);
+                self.resolve(unit, Outcome::Consumed);
+                result = deferred.state().is_some().then(|| deferred.consume());
+            }
+        }
+        result
+    }
+
+    /// Executes all remaining parts of unit and tells if it has not failed while doing that.
+    fn run_to_end(&mut self, id: Id) -> bool {
+        self.wake(id);
+        let isolate = self.isolate_panics;
+        let emitted = &mut self.emitted;
+        let time = self.time;
+        let host = Host {
+            spawner: &self.spawner,
+            resources: &self.resources,
+        };
+        let result = match self.registry.get_mut(&id) {
+            Some(deferred) => guard(isolate, || {
+                deferred.env.time = time;
+                watched(id, deferred, emitted, |deferred| {
+                    while deferred.step_with(None, Some(&host)) {}
+                })
+            }),
+            None => return false,
+        };
+        self.adopt_spawned();
+        match result {
+            Ok(()) => true,
+            Err(failure) => {
+                self.fail(id, failure);
+                false
+            }
         }
     }
 
@@ -302,22 +1142,115 @@ impl<S> DeferredManager<S> {
     /// # }
     /// ```
     pub fn resume_all(&mut self) {
-        let mut registry = HashMap::new();
-        let kv = self.registry.drain().filter_map(|(i, d)| {
-            if let Some(d) = d.resume() {
-                if d.can_resume() {
-                    Some((i, d))
-                } else {
-                    None
+        self.sweep_handles();
+        let isolate = self.isolate_panics;
+        let registry = &mut self.registry;
+        let Waits {
+            runnable,
+            subscribers,
+        } = self.waits.get_mut();
+        let emitted = &mut self.emitted;
+        let time = self.time;
+        let host = Host {
+            spawner: &self.spawner,
+            resources: &self.resources,
+        };
+        let mut completed = vec![];
+        let mut failures = vec![];
+        runnable.retain(|id| {
+            let deferred = match registry.get_mut(id) {
+                Some(deferred) => deferred,
+                None => return false,
+            };
+            deferred.env.time = time;
+            match guard(isolate, || {
+                watched(*id, deferred, emitted, |deferred| {
+                    deferred.resume_hosted(None, Some(&host))
+                })
+            }) {
+                Ok(Status::Progressed) => match deferred.waiting_for() {
+                    Some(event) => {
+                        subscribe(*id, event, subscribers);
+                        false
+                    }
+                    None => true,
+                },
+                Ok(_) => {
+                    completed.push(*id);
+                    false
+                }
+                Err(failure) => {
+                    failures.push((*id, failure));
+                    false
                 }
-            } else {
-                None
             }
         });
-        for (i, d) in kv {
-            registry.insert(i, d);
+        self.adopt_spawned();
+        for id in completed {
+            self.complete(id);
+        }
+        for (id, failure) in failures {
+            self.fail(id, failure);
+        }
+    }
+
+    /// Wakes all units waiting for given event and returns their number.
+    ///
+    /// # Arguments
+    /// * `event` - event name.
+    pub fn signal(&mut self, event: &str) -> usize {
+        self.sweep_handles();
+        let waits = self.waits.get_mut();
+        let ids = match waits.subscribers.remove(event) {
+            Some(ids) => ids,
+            None => return 0,
+        };
+        let mut count = 0;
+        for id in ids {
+            if let Some(deferred) = self.registry.get_mut(&id) {
+                if deferred.signal(event) {
+                    waits.runnable.insert(id);
+                    count += 1;
+                }
+            }
         }
-        self.registry = registry;
+        count
+    }
+
+    /// Gets number of units sleeping until given event.
+    ///
+    /// # Arguments
+    /// * `event` - event name.
+    #[inline]
+    pub fn waiting_count(&self, event: &str) -> usize {
+        self.waits
+            .borrow()
+            .subscribers
+            .get(event)
+            .map_or(0, |ids| ids.len())
+    }
+
+    /// Tells if there is any unit that is not sleeping, so host loop knows if it can sleep.
+    #[inline]
+    pub fn has_runnable(&self) -> bool {
+        !self.waits.borrow().runnable.is_empty()
+    }
+
+    /// Gets number of units that are not sleeping.
+    #[inline]
+    pub fn runnable_count(&self) -> usize {
+        self.waits.borrow().runnable.len()
+    }
+
+    /// Gets event that unit with given id sleeps until, if it is sleeping.
+    ///
+    /// # Arguments
+    /// * `id` - deferred execution id (got from calling `run()` method).
+    #[inline]
+    pub fn waiting_for(&self, id: Id) -> Option<&str> {
+        self.registry
+            .get(&id)
+            .and_then(|deferred| deferred.waiting_for())
     }
 
     /// Consume all deferred execution units and return vector of id-state pairs.
@@ -353,24 +1286,527 @@ impl<S> DeferredManager<S> {
     /// # }
     /// ```
     pub fn consume_all(&mut self) -> Vec<(Id, S)> {
-        self.registry
-            .drain()
-            .filter_map(|(i, d)| {
-                if d.can_resume() {
-                    Some((i, d.consume()))
+        self.sweep_handles();
+        let isolate = self.isolate_panics;
+        let failed = &mut self.failed;
+        let emitted = &mut self.emitted;
+        let time = self.time;
+        let joining = core::mem::take(&mut self.joining);
+        let host = Host {
+            spawner: &self.spawner,
+            resources: &self.resources,
+        };
+        *self.waits.get_mut() = Waits::default();
+        self.parents.clear();
+        self.children.clear();
+        let result = core::mem::take(&mut self.registry)
+            .into_iter()
+            .filter_map(|(i, mut d)| {
+                d.env.time = time;
+                if d.can_resume() || joining.contains(&i) {
+                    match guard(isolate, || finish(i, d, emitted, &host)) {
+                        Ok(state) => Some((i, state)),
+                        Err(failure) => {
+                            emitted.remove(&i);
+                            failed.insert(i, failure);
+                            None
+                        }
+                    }
                 } else {
                     None
                 }
             })
-            .collect::<Vec<(Id, S)>>()
+            .collect::<Vec<(Id, S)>>();
+        if !self.joins.is_empty() {
+            let consumed = result.iter().map(|(id, _)| *id).collect::<Set<_>>();
+            for (id, handle) in core::mem::take(&mut self.joins) {
+                handle.resolve(if consumed.contains(&id) {
+                    Outcome::Consumed
+                } else if let Some(failure) = self.failed.get(&id) {
+                    Outcome::Failed(failure.clone())
+                } else {
+                    Outcome::Cancelled
+                });
+            }
+        }
+        self.adopt_spawned();
+        result
+    }
+
+    fn resume_steps<F>(&mut self, id: Id, f: F) -> usize
+    where
+        F: FnOnce(&mut Deferred<S>, &Host<S>, &mut usize),
+    {
+        self.sweep_handles();
+        self.wake(id);
+        let isolate = self.isolate_panics;
+        let emitted = &mut self.emitted;
+        let time = self.time;
+        let host = Host {
+            spawner: &self.spawner,
+            resources: &self.resources,
+        };
+        let mut steps = 0;
+        let counter = &mut steps;
+        let result = match self.registry.get_mut(&id) {
+            Some(deferred) => guard(isolate, || {
+                deferred.env.time = time;
+                watched(id, deferred, emitted, |deferred| {
+                    f(deferred, &host, counter);
+                    !deferred.can_resume()
+                })
+            }),
+            None => return 0,
+        };
+        self.adopt_spawned();
+        match result {
+            Ok(completed) => {
+                if completed {
+                    self.complete(id);
+                } else {
+                    self.settle(id);
+                }
+                steps
+            }
+            Err(failure) => {
+                self.fail(id, failure);
+                steps
+            }
+        }
+    }
+
+    fn resume_with_status<F>(&mut self, id: Id, f: F) -> bool
+    where
+        F: FnOnce(&mut Deferred<S>, &Host<S>) -> Status,
+    {
+        self.sweep_handles();
+        self.wake(id);
+        let isolate = self.isolate_panics;
+        let emitted = &mut self.emitted;
+        let time = self.time;
+        let host = Host {
+            spawner: &self.spawner,
+            resources: &self.resources,
+        };
+        let status = match self.registry.get_mut(&id) {
+            Some(deferred) => guard(isolate, || {
+                deferred.env.time = time;
+                watched(id, deferred, emitted, |deferred| f(deferred, &host))
+            }),
+            None => return false,
+        };
+        self.adopt_spawned();
+        match status {
+            Ok(Status::Progressed) => {
+                self.settle(id);
+                true
+            }
+            Ok(status) => {
+                self.complete(id);
+                status != Status::Idle
+            }
+            Err(failure) => {
+                self.fail(id, failure);
+                true
+            }
+        }
+    }
+
+    fn fail(&mut self, id: Id, failure: Failure) {
+        self.resolve(id, Outcome::Failed(failure.clone()));
+        self.cancel_tree(id);
+        self.failed.insert(id, failure);
+    }
+
+    /// Removes unit that has executed all its parts, or keeps it joining when it has attached
+    /// children. Unit that has lost its state to panic caught by host is failed instead.
+    fn complete(&mut self, id: Id) {
+        self.waits.get_mut().runnable.remove(&id);
+        if self.state(id).is_none() {
+            self.fail(id, Failure::Panic(LOST_STATE.into()));
+            return;
+        }
+        if self.children.contains_key(&id) {
+            self.joining.insert(id);
+        } else if let Some(deferred) = self.registry.remove(&id) {
+            self.release(id);
+            self.resolve_completed(id, deferred);
+        }
+    }
+
+    /// Removes unit together with all its descendants and calls their cleanup.
+    fn cancel_tree(&mut self, id: Id) -> bool {
+        let tree = self.detach_tree(id);
+        for unit in tree.into_iter().skip(1).rev() {
+            self.cancel_unit(unit);
+        }
+        self.cancel_unit(id)
+    }
+
+    /// Unlinks all descendants of unit from their parents and returns ids of the whole tree,
+    /// starting with given unit, so that every unit comes before its descendants.
+    fn detach_tree(&mut self, id: Id) -> Vec<Id> {
+        let mut tree = vec![];
+        let mut stack = vec![id];
+        while let Some(unit) = stack.pop() {
+            tree.push(unit);
+            let children = self.children.remove(&unit).unwrap_or_default();
+            for child in children.iter().rev() {
+                self.parents.remove(child);
+                stack.push(*child);
+            }
+        }
+        tree
+    }
+
+    fn cancel_unit(&mut self, id: Id) -> bool {
+        let joining = self.joining.remove(&id);
+        match self.remove(id) {
+            Some(deferred) => {
+                self.emitted.remove(&id);
+                self.release(id);
+                if joining {
+                    self.resolve(id, Outcome::Cancelled);
+                } else {
+                    self.clean_up(id, deferred);
+                }
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Calls cleanup of cancelled unit and records its panic as failure of that unit.
+    fn clean_up(&mut self, id: Id, deferred: Deferred<S>) {
+        let result = guard(self.isolate_panics, || {
+            deferred.cleanup();
+            Ok(())
+        });
+        match result {
+            Ok(()) => self.resolve(id, Outcome::Cancelled),
+            Err(failure) => {
+                self.resolve(id, Outcome::Failed(failure.clone()));
+                self.failed.insert(id, failure);
+            }
+        }
+    }
+
+    fn link(&mut self, parent: Id, child: Id) {
+        self.parents.insert(child, parent);
+        self.children.entry(parent).or_default().push(child);
+    }
+
+    /// Unlinks unit that has left registry from its parent.
+    fn release(&mut self, id: Id) {
+        if let Some(parent) = self.parents.remove(&id) {
+            self.unlink(parent, id);
+        }
+    }
+
+    /// Removes child from children of parent and completes parent that was waiting only for it.
+    fn unlink(&mut self, parent: Id, child: Id) {
+        let empty = match self.children.get_mut(&parent) {
+            Some(children) => {
+                children.retain(|id| *id != child);
+                children.is_empty()
+            }
+            None => false,
+        };
+        if empty {
+            self.children.remove(&parent);
+            if self.joining.remove(&parent) {
+                if let Some(deferred) = self.registry.remove(&parent) {
+                    self.release(parent);
+                    self.resolve_completed(parent, deferred);
+                }
+            }
+        }
+    }
+
+    /// Passes outcome to handle of unit that has left registry.
+    fn resolve(&mut self, id: Id, outcome: Outcome<S>) {
+        if let Some(handle) = self.joins.remove(&id) {
+            handle.resolve(outcome);
+        }
+    }
+
+    fn resolve_completed(&mut self, id: Id, deferred: Deferred<S>) {
+        if let Some(handle) = self.joins.remove(&id) {
+            handle.resolve(Outcome::Completed(deferred.consume()));
+        }
+    }
+
+    /// Cancels units which handles were all dropped without detaching.
+    fn sweep_handles(&mut self) {
+        let dropped = self
+            .joins
+            .iter()
+            .filter(|(_, handle)| handle.is_orphaned())
+            .map(|(id, _)| *id)
+            .collect::<Vec<_>>();
+        for id in dropped {
+            if let Some(handle) = self.joins.remove(&id) {
+                if !handle.is_detached() {
+                    self.cancel_tree(id);
+                }
+            }
+        }
+    }
+
+    fn generate_id(&mut self) -> Id {
+        self.spawner.next_id()
+    }
+
+    fn adopt_spawned(&mut self) {
+        for (id, parent, deferred) in self.spawner.take_pending() {
+            match parent {
+                Some(parent) if !self.registry.contains_key(&parent) => self.clean_up(id, deferred),
+                Some(parent) => {
+                    self.insert(id, deferred);
+                    self.link(parent, id);
+                }
+                None => self.insert(id, deferred),
+            }
+        }
+    }
+
+    fn insert(&mut self, id: Id, mut deferred: Deferred<S>) {
+        if let (Some(limits), None) = (self.default_limits, deferred.limits()) {
+            deferred = deferred.with_limits(limits);
+        }
+        if let (Some(clock), true) = (self.clock, deferred.needs_clock()) {
+            deferred = deferred.with_clock(clock);
+        }
+        deferred.env.id = Some(id);
+        deferred.env.started = self.time;
+        deferred.env.time = self.time;
+        settle(id, &deferred, self.waits.get_mut());
+        self.registry.insert(id, deferred);
+    }
+
+    fn schedule(&mut self, id: Id, time: Tick, timer: Timer<S>) {
+        self.scheduled.insert(id, time);
+        self.timers.insert((time, id), timer);
+    }
+
+    fn remove(&mut self, id: Id) -> Option<Deferred<S>> {
+        self.waits.get_mut().runnable.remove(&id);
+        self.wake(id);
+        self.registry.remove(&id)
+    }
+
+    /// Drops unit from subscribers of event it sleeps until, before it gets resumed or removed.
+    fn wake(&mut self, id: Id) {
+        if let Some(event) = self.registry.get(&id).and_then(|d| d.waiting_for()) {
+            unsubscribe(id, event, &mut self.waits.get_mut().subscribers);
+        }
+    }
+
+    fn settle(&mut self, id: Id) {
+        if let Some(deferred) = self.registry.get(&id) {
+            settle(id, deferred, self.waits.get_mut());
+        }
     }
 }
 
 impl<S> Default for DeferredManager<S> {
     fn default() -> Self {
         Self {
-            registry: HashMap::new(),
-            id_generator: 0,
+            registry: Map::new(),
+            waits: RefCell::new(Waits::default()),
+            timers: BTreeMap::new(),
+            scheduled: Map::new(),
+            time: 0,
+            failed: Map::new(),
+            spawner: Spawner::new(),
+            resources: Resources::new(),
+            parents: Map::new(),
+            children: Map::new(),
+            joining: Set::new(),
+            joins: Map::new(),
+            isolate_panics: false,
+            default_limits: None,
+            clock: None,
+            emitted: Map::new(),
+        }
+    }
+}
+
+impl<S> Drop for DeferredManager<S> {
+    fn drop(&mut self) {
+        self.spawner.take_pending();
+    }
+}
+
+/// Executes given function, catching its panic when isolation is enabled.
+#[cfg(feature = "std")]
+fn guard<R, F: FnOnce() -> Result<R, Failure>>(isolate: bool, f: F) -> Result<R, Failure> {
+    if !isolate {
+        return f();
+    }
+    std::panic::catch_unwind(std::panic::AssertUnwindSafe(f)).unwrap_or_else(|payload| {
+        let message = if let Some(message) = payload.downcast_ref::<&str>() {
+            (*message).into()
+        } else if let Some(message) = payload.downcast_ref::<String>() {
+            message.clone()
+        } else {
+            "Box<dyn Any>".into()
+        };
+        Err(Failure::Panic(message))
+    })
+}
+
+#[cfg(not(feature = "std"))]
+fn guard<R, F: FnOnce() -> Result<R, Failure>>(_: bool, f: F) -> Result<R, Failure> {
+    f()
+}
+
+/// Units that are not sleeping and units sleeping until events.
+#[derive(Default)]
+struct Waits {
+    runnable: Set<Id>,
+    subscribers: Map<Event, Set<Id>>,
+}
+
+/// Mutable access to deferred execution unit of `DeferredManager`, got from `get_mut()` or
+/// `iter_mut()`. When dropped, it updates whether unit sleeps, in case its wait got changed with
+/// `Deferred::signal()` or by resuming it in place.
+pub struct UnitMut<'a, S> {
+    id: Id,
+    waiting: Option<Event>,
+    deferred: &'a mut Deferred<S>,
+    waits: &'a RefCell<Waits>,
+    joining: &'a Set<Id>,
+}
+
+impl<'a, S> UnitMut<'a, S> {
+    fn new(
+        id: Id,
+        deferred: &'a mut Deferred<S>,
+        waits: &'a RefCell<Waits>,
+        joining: &'a Set<Id>,
+    ) -> Self {
+        Self {
+            id,
+            waiting: deferred.waiting.clone(),
+            deferred,
+            waits,
+            joining,
         }
     }
 }
+
+impl<S> Deref for UnitMut<'_, S> {
+    type Target = Deferred<S>;
+
+    fn deref(&self) -> &Deferred<S> {
+        self.deferred
+    }
+}
+
+impl<S> DerefMut for UnitMut<'_, S> {
+    fn deref_mut(&mut self) -> &mut Deferred<S> {
+        self.deferred
+    }
+}
+
+impl<S> Drop for UnitMut<'_, S> {
+    fn drop(&mut self) {
+        if self.deferred.waiting_for() == self.waiting.as_deref() {
+            return;
+        }
+        let waits = &mut *self.waits.borrow_mut();
+        if let Some(event) = self.waiting.take() {
+            unsubscribe(self.id, &event, &mut waits.subscribers);
+        }
+        if !self.joining.contains(&self.id) {
+            settle(self.id, self.deferred, waits);
+        }
+    }
+}
+
+/// Puts unit to sleep if it waits for event, otherwise makes it runnable.
+fn settle<S>(id: Id, deferred: &Deferred<S>, waits: &mut Waits) {
+    match deferred.waiting_for() {
+        Some(event) => {
+            waits.runnable.remove(&id);
+            subscribe(id, event, &mut waits.subscribers);
+        }
+        None => {
+            waits.runnable.insert(id);
+        }
+    }
+}
+
+fn subscribe(id: Id, event: &str, subscribers: &mut Map<Event, Set<Id>>) {
+    match subscribers.get_mut(event) {
+        Some(ids) => {
+            ids.insert(id);
+        }
+        None => {
+            subscribers.insert(Event::Owned(event.into()), Set::from_iter([id]));
+        }
+    }
+}
+
+fn unsubscribe(id: Id, event: &str, subscribers: &mut Map<Event, Set<Id>>) {
+    if let Some(ids) = subscribers.get_mut(event) {
+        ids.remove(&id);
+        if ids.is_empty() {
+            subscribers.remove(event);
+        }
+    }
+}
+
+/// Executes given function on unit, collects items it has emitted and turns exceeded limit into
+/// failure.
+fn watched<S, R, F>(
+    id: Id,
+    deferred: &mut Deferred<S>,
+    emitted: &mut Map<Id, Vec<SendValue>>,
+    f: F,
+) -> Result<R, Failure>
+where
+    F: FnOnce(&mut Deferred<S>) -> R,
+{
+    let result = f(deferred);
+    if deferred.emitted_count() > 0 {
+        emitted
+            .entry(id)
+            .or_default()
+            .extend(deferred.emitted.drain(..));
+    }
+    match deferred.limit_exceeded() {
+        Some(exceeded) => Err(Failure::Limit(exceeded)),
+        None => Ok(result),
+    }
+}
+
+/// Executes all remaining parts of unit and returns its final state.
+fn finish<S>(
+    id: Id,
+    mut deferred: Deferred<S>,
+    emitted: &mut Map<Id, Vec<SendValue>>,
+    host: &Host<S>,
+) -> Result<S, Failure> {
+    watched(id, &mut deferred, emitted, |deferred| {
+        while deferred.step_with(None, Some(host)) {}
+    })?;
+    Ok(deferred.consume())
+}
+
+impl<S> Extend<Deferred<S>> for DeferredManager<S> {
+    fn extend<I: IntoIterator<Item = Deferred<S>>>(&mut self, iter: I) {
+        for deferred in iter {
+            self.run(deferred);
+        }
+    }
+}
+
+impl<S> FromIterator<Deferred<S>> for DeferredManager<S> {
+    fn from_iter<I: IntoIterator<Item = Deferred<S>>>(iter: I) -> Self {
+        let mut manager = Self::new();
+        manager.extend(iter);
+        manager
+    }
+}
@@ -0,0 +1,167 @@
+use crate::deferred_manager::{Failure, Id};
+use crate::lock::{Guard, Lock, Ptr};
+use core::{
+    ops::Deref,
+    sync::atomic::{AtomicBool, Ordering},
+};
+
+/// Final outcome of deferred execution unit observed with `JoinHandle`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Outcome<S> {
+    /// Unit has completed with given state.
+    Completed(S),
+    /// Unit has failed.
+    Failed(Failure),
+    /// Unit was cancelled.
+    Cancelled,
+    /// Unit has completed but its state was taken with `JoinHandle::take()` or
+    /// `DeferredManager::consume()`.
+    Consumed,
+}
+
+struct Shared<S> {
+    id: Id,
+    outcome: Lock<Option<Outcome<S>>>,
+    detached: AtomicBool,
+}
+
+/// Reference to outcome of unit or to its final state, got from `JoinHandle`. Handle is locked
+/// while it exists.
+pub struct OutcomeRef<'a, S, T> {
+    guard: Guard<'a, Option<Outcome<S>>>,
+    get: fn(&Option<Outcome<S>>) -> Option<&T>,
+}
+
+impl<'a, S, T> OutcomeRef<'a, S, T> {
+    fn new(
+        guard: Guard<'a, Option<Outcome<S>>>,
+        get: fn(&Option<Outcome<S>>) -> Option<&T>,
+    ) -> Option<Self> {
+        get(&guard)?;
+        Some(Self { guard, get })
+    }
+}
+
+impl<S, T> Deref for OutcomeRef<'_, S, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        (self.get)(&self.guard).expect("Outcome of unit has changed while it is referenced")
+    }
+}
+
+/// Handle of deferred execution unit got from `DeferredManager::spawn()`, used to wait for its
+/// completion and get its final state.
+///
+/// Handle can be cloned to let several waiters observe the same outcome. When all clones are
+/// dropped before unit finishes, manager cancels it on next operation that resumes units or moves
+/// time, unless it was detached with `detach()`.
+///
+/// # Example
+/// ```
+/// # #[macro_use] extern crate deferred;
+/// # use deferred::*;
+/// # fn main() {
+/// let mut manager = DeferredManager::new();
+/// let handle = manager.spawn(deferred!(1, [
+///     |c| state!(c.state() + 1),
+///     |c| state!(c.state() * 2)
+/// ]));
+/// let waiter = handle.clone();
+/// let dropped = manager.spawn(deferred!(0, [|c| state!(c.state()), |c| state!(c.state())]));
+/// let dropped_id = dropped.id();
+/// drop(dropped);
+///
+/// manager.resume_all();
+/// assert!(!handle.is_finished());
+/// assert!(!manager.has(dropped_id));
+/// manager.resume_all();
+/// assert_eq!(waiter.state().as_deref(), Some(&4));
+/// assert_eq!(handle.take(), Some(4));
+/// assert_eq!(waiter.outcome().as_deref(), Some(&Outcome::Consumed));
+/// # }
+/// ```
+pub struct JoinHandle<S> {
+    shared: Ptr<Shared<S>>,
+}
+
+impl<S> JoinHandle<S> {
+    pub(crate) fn new(id: Id) -> Self {
+        Self {
+            shared: Ptr::new(Shared {
+                id,
+                outcome: Lock::new(None),
+                detached: AtomicBool::new(false),
+            }),
+        }
+    }
+
+    /// Gets id of unit.
+    #[inline]
+    pub fn id(&self) -> Id {
+        self.shared.id
+    }
+
+    /// Tells if unit has finished, either completed, failed or cancelled.
+    #[inline]
+    pub fn is_finished(&self) -> bool {
+        self.shared.outcome.lock().is_some()
+    }
+
+    /// Tells if unit was detached.
+    #[inline]
+    pub fn is_detached(&self) -> bool {
+        self.shared.detached.load(Ordering::Acquire)
+    }
+
+    /// Gets outcome of unit, or `None` if it has not finished yet.
+    pub fn outcome(&self) -> Option<OutcomeRef<'_, S, Outcome<S>>> {
+        OutcomeRef::new(self.shared.outcome.lock(), Option::as_ref)
+    }
+
+    /// Gets final state of unit, or `None` if it has not completed or its state was taken.
+    pub fn state(&self) -> Option<OutcomeRef<'_, S, S>> {
+        OutcomeRef::new(self.shared.outcome.lock(), |outcome| match outcome {
+            Some(Outcome::Completed(state)) => Some(state),
+            _ => None,
+        })
+    }
+
+    /// Takes final state of unit, or returns `None` if it has not completed or its state was
+    /// already taken. Other clones of handle then observe `Outcome::Consumed`.
+    pub fn take(&self) -> Option<S> {
+        let mut outcome = self.shared.outcome.lock();
+        match outcome.take() {
+            Some(Outcome::Completed(state)) => {
+                *outcome = Some(Outcome::Consumed);
+                Some(state)
+            }
+            other => {
+                *outcome = other;
+                None
+            }
+        }
+    }
+
+    /// Consumes handle and detaches unit, so it keeps running when all handles are dropped.
+    pub fn detach(self) {
+        self.shared.detached.store(true, Ordering::Release);
+    }
+
+    pub(crate) fn resolve(&self, outcome: Outcome<S>) {
+        *self.shared.outcome.lock() = Some(outcome);
+    }
+
+    /// Tells if only manager holds this handle.
+    pub(crate) fn is_orphaned(&self) -> bool {
+        Ptr::strong_count(&self.shared) == 1
+    }
+}
+
+impl<S> Clone for JoinHandle<S> {
+    fn clone(&self) -> Self {
+        Self {
+            shared: self.shared.clone(),
+        }
+    }
+}
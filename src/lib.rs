@@ -45,6 +45,11 @@
 //! `Futures` or threads but you still need to run some of your code asynchronously, most likely
 //! execute heavy/long calculations "in background" and you cannot make browser freeze.
 //!
+//! # It does not need `std`
+//! Crate has default-on `std` feature. When you disable it, crate depends only on `alloc` so it
+//! can be used in embedded or custom runtime builds - the only difference is that
+//! `DeferredManager` stores its units in ordered map instead of hash map.
+//!
 //! # Need to use undefined state type? Look, there is `Value` wrapper!
 //! Sometimes you cannot have the same context input and output types, for example:
 //! ```ignore
@@ -98,14 +103,45 @@
 //! # }
 //! ```
 
+#![cfg_attr(not(feature = "std"), no_std)]
+
+extern crate alloc;
+
+pub mod any_deferred_manager;
+pub mod array_deferred;
+pub mod blackboard;
 pub mod context;
+pub mod debug_deferred;
 pub mod deferred;
+pub mod deferred_builder;
 pub mod deferred_manager;
+pub mod join_handle;
+pub mod limits;
+pub mod linear;
+mod lock;
 mod macros;
+pub mod resources;
+pub mod spawner;
+#[allow(clippy::bool_assert_comparison)]
 mod tests;
 pub mod value;
 
+pub use crate::any_deferred_manager::*;
+pub use crate::array_deferred::*;
+pub use crate::blackboard::*;
 pub use crate::context::*;
+pub use crate::debug_deferred::*;
 pub use crate::deferred::*;
+pub use crate::deferred_builder::*;
 pub use crate::deferred_manager::*;
+pub use crate::join_handle::*;
+pub use crate::limits::{Clock, LimitExceeded, Limits};
+pub use crate::linear::Linear;
+pub use crate::resources::*;
+pub use crate::spawner::*;
 pub use crate::value::*;
+
+#[doc(hidden)]
+pub mod __private {
+    pub use alloc::{boxed::Box, vec};
+}
@@ -0,0 +1,193 @@
+use alloc::vec::Vec;
+use core::{fmt, time::Duration};
+
+/// Limits of deferred execution unit, used to stop runaway units that never complete or grow
+/// without bound. Every limit is optional and `None` means no limit.
+///
+/// # Example
+/// ```
+/// # #[macro_use] extern crate deferred;
+/// # use deferred::*;
+/// # fn main() {
+/// fn forever(c: Context<i32>) -> Context<i32> {
+///     let v = c.state() + 1;
+///     deferred!(v, [forever, |c| state!(c.state())]).into()
+/// }
+///
+/// let limits = Limits {
+///     max_steps: Some(1000),
+///     max_depth: Some(10),
+///     ..Default::default()
+/// };
+/// let mut d = deferred!(0, [forever]).with_limits(limits);
+/// d.resume_in_place();
+/// assert_eq!(d.limit_exceeded(), Some(LimitExceeded::Depth(10)));
+/// assert!(!d.can_resume());
+/// assert_eq!(d.state(), Some(&11));
+/// # }
+/// ```
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct Limits {
+    /// Maximal number of executed parts, including parts that produced subroutines.
+    pub max_steps: Option<usize>,
+    /// Maximal number of nested subroutines running at once. Subroutine returned by the last part
+    /// of another one replaces it and does not increase depth.
+    pub max_depth: Option<usize>,
+    /// Maximal number of parts waiting for execution, used as a measure of memory used by unit.
+    pub max_parts: Option<usize>,
+    /// Maximal total time spent on executing parts, measured with clock set with
+    /// `Deferred::with_clock()` or `DeferredManager::set_clock()`. Without such clock, `std`
+    /// feature provides monotonic one, except for `wasm32-unknown-unknown` target that has no
+    /// time source - this limit is not checked there then.
+    pub max_duration: Option<Duration>,
+}
+
+/// Clock used to measure time of executing parts, giving time passed since any fixed moment.
+pub type Clock = fn() -> Duration;
+
+/// Gets clock used when host has not set any.
+#[cfg(all(
+    feature = "std",
+    not(all(target_arch = "wasm32", target_os = "unknown"))
+))]
+fn default_clock() -> Option<Clock> {
+    fn now() -> Duration {
+        static START: std::sync::OnceLock<std::time::Instant> = std::sync::OnceLock::new();
+        START.get_or_init(std::time::Instant::now).elapsed()
+    }
+
+    Some(now)
+}
+
+#[cfg(not(all(
+    feature = "std",
+    not(all(target_arch = "wasm32", target_os = "unknown"))
+)))]
+fn default_clock() -> Option<Clock> {
+    None
+}
+
+/// Limit that was exceeded by deferred execution unit, with value of that limit.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LimitExceeded {
+    /// Too many parts were executed.
+    Steps(usize),
+    /// Subroutines were nested too deep.
+    Depth(usize),
+    /// Too many parts were waiting for execution.
+    Parts(usize),
+    /// Executing parts took too long.
+    Duration(Duration),
+}
+
+impl fmt::Display for LimitExceeded {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            LimitExceeded::Steps(limit) => write!(f, "Executed more than {} parts", limit),
+            LimitExceeded::Depth(limit) => {
+                write!(f, "Nested subroutines deeper than {} levels", limit)
+            }
+            LimitExceeded::Parts(limit) => write!(f, "Queued more than {} parts", limit),
+            LimitExceeded::Duration(limit) => write!(f, "Executed parts longer than {:?}", limit),
+        }
+    }
+}
+
+#[cfg(feature = "std")]
+impl std::error::Error for LimitExceeded {}
+
+/// Tracks number of executed parts and nesting of subroutines of deferred execution unit.
+#[derive(Default)]
+pub(crate) struct Tracker {
+    pub(crate) steps: usize,
+    /// Number of queued parts that belong to callers of each running subroutine.
+    frames: Vec<usize>,
+}
+
+impl Tracker {
+    /// Gets number of nested subroutines running at once.
+    #[inline]
+    pub(crate) fn depth(&self) -> usize {
+        self.frames.len()
+    }
+
+    /// Called after part has produced state, with number of parts left in queue.
+    pub(crate) fn after_state(&mut self, queued: usize) {
+        self.leave_frames(queued);
+    }
+
+    /// Called after part has produced subroutine, with number of parts left in queue before and
+    /// after its parts were spliced in.
+    pub(crate) fn after_subroutine(&mut self, queued_before: usize, queued_after: usize) {
+        self.leave_frames(queued_before);
+        self.frames.push(queued_before);
+        self.leave_frames(queued_after);
+    }
+
+    fn leave_frames(&mut self, queued: usize) {
+        while self.frames.last().is_some_and(|frame| *frame >= queued) {
+            self.frames.pop();
+        }
+    }
+}
+
+/// Checks usage of deferred execution unit against its limits.
+pub(crate) struct Watchdog {
+    pub(crate) limits: Limits,
+    pub(crate) exceeded: Option<LimitExceeded>,
+    pub(crate) clock: Option<Clock>,
+    elapsed: Duration,
+}
+
+impl Watchdog {
+    pub(crate) fn new(limits: Limits, clock: Option<Clock>) -> Self {
+        Self {
+            limits,
+            exceeded: None,
+            clock,
+            elapsed: Duration::default(),
+        }
+    }
+
+    /// Gets clock to measure time of executing parts with, when there is duration limit.
+    pub(crate) fn duration_clock(&self) -> Option<Clock> {
+        self.limits.max_duration?;
+        self.clock.or_else(default_clock)
+    }
+
+    /// Called before part gets executed, with number of parts executed so far.
+    pub(crate) fn check_steps(&self, steps: usize) -> Result<(), LimitExceeded> {
+        match self.limits.max_steps {
+            Some(limit) if steps >= limit => Err(LimitExceeded::Steps(limit)),
+            _ => Ok(()),
+        }
+    }
+
+    /// Called after part has produced subroutine, with current depth and number of parts left in
+    /// queue.
+    pub(crate) fn check_subroutine(
+        &self,
+        depth: usize,
+        queued: usize,
+    ) -> Result<(), LimitExceeded> {
+        if let Some(limit) = self.limits.max_depth {
+            if depth > limit {
+                return Err(LimitExceeded::Depth(limit));
+            }
+        }
+        if let Some(limit) = self.limits.max_parts {
+            if queued > limit {
+                return Err(LimitExceeded::Parts(limit));
+            }
+        }
+        Ok(())
+    }
+
+    pub(crate) fn add_elapsed(&mut self, elapsed: Duration) -> Result<(), LimitExceeded> {
+        self.elapsed += elapsed;
+        match self.limits.max_duration {
+            Some(limit) if self.elapsed > limit => Err(LimitExceeded::Duration(limit)),
+            _ => Ok(()),
+        }
+    }
+}
@@ -0,0 +1,167 @@
+use crate::context::*;
+use core::convert::Infallible;
+
+#[cfg(feature = "macros")]
+pub use deferred_macros::deferred;
+
+/// State of deferred execution built from linear function - it holds either local variables
+/// carried between steps or final result.
+///
+/// Local variables are usually an enum with one variant per point at which function gets
+/// suspended, so they stay typed and unboxed. Such enum is generated by `#[deferred]` attribute,
+/// but it can be written by hand as well.
+///
+/// # Example
+/// ```
+/// # #[macro_use] extern crate deferred;
+/// # use deferred::*;
+/// # use deferred::linear::*;
+/// # fn main() {
+/// enum Locals {
+///     Start(i32),
+///     Next(i32, i32),
+/// }
+///
+/// fn foo(v: i32) -> Deferred<Linear<Locals, String>> {
+///     deferred!(Linear::new(Locals::Start(v)), [|c| advance(c, |locals| match locals {
+///         Locals::Start(v) => Resume::Yield(Locals::Next(v, v + 1)),
+///         Locals::Next(v, w) => Resume::Done(format!("{} {}", v, w)),
+///     })])
+/// }
+///
+/// let d = foo(1).resume().unwrap();
+/// assert!(!d.state().unwrap().is_done());
+/// let d = d.resume().unwrap();
+/// assert_eq!(d.state().unwrap().result(), Some(&"1 2".to_owned()));
+/// assert_eq!(d.consume().into_result(), Some("1 2".to_owned()));
+/// # }
+/// ```
+pub struct Linear<L, R> {
+    locals: Option<L>,
+    result: Option<R>,
+}
+
+impl<L, R> Linear<L, R> {
+    /// Creates new state of linear function that has not started yet.
+    ///
+    /// # Arguments
+    /// * `locals` - local variables passed to first step (most likely function arguments).
+    #[inline]
+    pub fn new(locals: L) -> Self {
+        Self {
+            locals: Some(locals),
+            result: None,
+        }
+    }
+
+    /// Tells if function has completed and its result is available.
+    #[inline]
+    pub fn is_done(&self) -> bool {
+        self.result.is_some()
+    }
+
+    /// Gets local variables carried to the next step.
+    #[inline]
+    pub fn locals(&self) -> Option<&L> {
+        self.locals.as_ref()
+    }
+
+    /// Gets reference to result of completed function.
+    #[inline]
+    pub fn result(&self) -> Option<&R> {
+        self.result.as_ref()
+    }
+
+    /// Consumes state and returns result of completed function.
+    #[inline]
+    pub fn into_result(self) -> Option<R> {
+        self.result
+    }
+}
+
+/// Outcome of single step of linear function.
+pub enum Resume<L, R> {
+    /// Function got suspended with local variables to carry to the next step.
+    Yield(L),
+    /// Function has completed with result.
+    Done(R),
+}
+
+/// Executes single step of linear function on its local variables. Logic part calling it gets
+/// executed again on next resume until function completes, so whole function needs just one part.
+///
+/// # Arguments
+/// * `context` - context passed to logic part.
+/// * `step` - closure that continues function from point it was suspended at.
+///
+/// # Panics
+/// * when function has already completed.
+pub fn advance<L, R, F>(context: Context<Linear<L, R>>, step: F) -> Context<Linear<L, R>>
+where
+    F: FnOnce(L) -> Resume<L, R>,
+{
+    let mut state = context.state();
+    let locals = state
+        .locals
+        .take()
+        .expect("Trying to resume linear function without local variables");
+    match step(locals) {
+        Resume::Yield(locals) => {
+            state.locals = Some(locals);
+            Context::from_state(state).again()
+        }
+        Resume::Done(result) => {
+            state.result = Some(result);
+            Context::from_state(state)
+        }
+    }
+}
+
+/// Splits value into one to continue with or residual to return early with, used by code
+/// generated with `#[deferred]` attribute in place of `?` operator.
+#[doc(hidden)]
+pub trait Branch {
+    type Output;
+    type Residual;
+
+    fn branch(self) -> Result<Self::Output, Self::Residual>;
+}
+
+impl<T, E> Branch for Result<T, E> {
+    type Output = T;
+    type Residual = Result<Infallible, E>;
+
+    fn branch(self) -> Result<T, Result<Infallible, E>> {
+        self.map_err(Err)
+    }
+}
+
+impl<T> Branch for Option<T> {
+    type Output = T;
+    type Residual = Option<Infallible>;
+
+    fn branch(self) -> Result<T, Option<Infallible>> {
+        self.ok_or(None)
+    }
+}
+
+/// Makes result of function returned early with residual of `Branch`.
+#[doc(hidden)]
+pub trait FromResidual<R> {
+    fn from_residual(residual: R) -> Self;
+}
+
+impl<T, E, F: From<E>> FromResidual<Result<Infallible, E>> for Result<T, F> {
+    fn from_residual(residual: Result<Infallible, E>) -> Self {
+        match residual {
+            Ok(never) => match never {},
+            Err(error) => Err(From::from(error)),
+        }
+    }
+}
+
+impl<T> FromResidual<Option<Infallible>> for Option<T> {
+    fn from_residual(_: Option<Infallible>) -> Self {
+        None
+    }
+}
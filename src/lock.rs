@@ -0,0 +1,74 @@
+//! Interior mutability for state shared between `DeferredManager` and its handles. With `std`
+//! feature it is backed by mutex so manager stays `Send`, otherwise by plain cell.
+
+#[cfg(not(feature = "std"))]
+use core::cell::{RefCell, RefMut};
+#[cfg(feature = "std")]
+use std::sync::{Mutex, MutexGuard, PoisonError, TryLockError};
+
+/// Pointer to state shared between manager and its handles.
+#[cfg(feature = "std")]
+pub(crate) type Ptr<T> = alloc::sync::Arc<T>;
+#[cfg(not(feature = "std"))]
+pub(crate) type Ptr<T> = alloc::rc::Rc<T>;
+
+#[cfg(feature = "std")]
+pub(crate) type Guard<'a, T> = MutexGuard<'a, T>;
+#[cfg(not(feature = "std"))]
+pub(crate) type Guard<'a, T> = RefMut<'a, T>;
+
+#[derive(Default)]
+pub(crate) struct Lock<T> {
+    #[cfg(feature = "std")]
+    inner: Mutex<T>,
+    #[cfg(not(feature = "std"))]
+    inner: RefCell<T>,
+}
+
+impl<T> Lock<T> {
+    pub(crate) fn new(value: T) -> Self {
+        Self {
+            #[cfg(feature = "std")]
+            inner: Mutex::new(value),
+            #[cfg(not(feature = "std"))]
+            inner: RefCell::new(value),
+        }
+    }
+
+    /// Locks value, waiting for other thread that holds it. Panic of part that held it does not
+    /// poison it.
+    #[cfg(feature = "std")]
+    pub(crate) fn lock(&self) -> Guard<'_, T> {
+        self.inner.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    #[cfg(not(feature = "std"))]
+    pub(crate) fn lock(&self) -> Guard<'_, T> {
+        self.inner.borrow_mut()
+    }
+
+    /// Locks value or returns `None` when it is already locked.
+    #[cfg(feature = "std")]
+    pub(crate) fn try_lock(&self) -> Option<Guard<'_, T>> {
+        match self.inner.try_lock() {
+            Ok(guard) => Some(guard),
+            Err(TryLockError::Poisoned(error)) => Some(error.into_inner()),
+            Err(TryLockError::WouldBlock) => None,
+        }
+    }
+
+    #[cfg(not(feature = "std"))]
+    pub(crate) fn try_lock(&self) -> Option<Guard<'_, T>> {
+        self.inner.try_borrow_mut().ok()
+    }
+
+    pub(crate) fn into_inner(self) -> T {
+        #[cfg(feature = "std")]
+        return self
+            .inner
+            .into_inner()
+            .unwrap_or_else(PoisonError::into_inner);
+        #[cfg(not(feature = "std"))]
+        return self.inner.into_inner();
+    }
+}
@@ -1,33 +1,174 @@
+/// Creates deferred execution from initial state and list of parts.
+///
+/// Each part can be one of:
+/// * `|c| ...` - logic part (closure that does not capture anything).
+/// * `move |c| ...` - closure part that can capture values.
+/// * `[...]` - nested list of parts executed as subroutine on current state.
+///
+/// and can be given a name used for diagnostics: `"name" => |c| ...`.
+///
+/// # Example
+/// ```
+/// # #[macro_use] extern crate deferred;
+/// # use deferred::*;
+/// # fn main() {
+/// fn foo(v: i32, n: i32) -> Deferred<i32> {
+///     deferred!(v, [
+///         "increment" => |c| state!(c.state() + 1),
+///         [
+///             |c| state!(c.state() * 2),
+///             move |c| state!(c.state() + n),
+///         ],
+///         "multiply" => move |c| state!(c.state() * n),
+///     ])
+/// }
+///
+/// let d = foo(1, 3);
+/// assert_eq!(d.next_part_name(), Some("increment"));
+/// assert_eq!(d.consume(), 21);
+/// # }
+/// ```
 #[macro_export]
 macro_rules! deferred {
-    ( $s:expr, [$($v:expr),*] ) => {
-        crate::Deferred::new($s, vec![$($v,)*])
+    ( $s:expr, [$($parts:tt)*] ) => {
+        $crate::Deferred::from_steps($s, $crate::__deferred_steps!([] $($parts)*))
     };
     ( $s:expr ) => {
-        crate::Deferred::new($s, vec![])
+        $crate::Deferred::new($s, $crate::__private::vec![])
+    };
+}
+
+#[doc(hidden)]
+#[macro_export]
+macro_rules! __deferred_steps {
+    ( [$($done:expr),*] ) => {
+        $crate::__private::vec![$($done),*]
+    };
+    ( [$($done:expr),*] $name:literal => $($rest:tt)+ ) => {
+        $crate::__deferred_steps!(@step [$($done),*] ($name) $($rest)+)
+    };
+    ( [$($done:expr),*] $($rest:tt)+ ) => {
+        $crate::__deferred_steps!(@step [$($done),*] () $($rest)+)
+    };
+    ( @step [$($done:expr),*] ($($name:literal)?) [$($parts:tt)*] $(, $($rest:tt)*)? ) => {
+        $crate::__deferred_steps!(
+            [$($done,)* $crate::__deferred_nested!([$($parts)*] $($parts)*)$(.named($name))?]
+            $($($rest)*)?
+        )
+    };
+    ( @step [$($done:expr),*] ($($name:literal)?) move |$c:ident| $v:expr $(, $($rest:tt)*)? ) => {
+        $crate::__deferred_steps!(
+            [$($done,)* $crate::Step::closure(move |$c| $v)$(.named($name))?]
+            $($($rest)*)?
+        )
+    };
+    ( @step [$($done:expr),*] ($($name:literal)?) $v:expr $(, $($rest:tt)*)? ) => {
+        $crate::__deferred_steps!(
+            [$($done,)* $crate::Step::new($v)$(.named($name))?]
+            $($($rest)*)?
+        )
+    };
+}
+
+/// Creates step that runs nested list of parts as subroutine - logic part when none of them
+/// captures values (so it does not need to be boxed), closure otherwise.
+#[doc(hidden)]
+#[macro_export]
+macro_rules! __deferred_nested {
+    ( [$($parts:tt)*] ) => {
+        $crate::Step::new(|c| $crate::subdeferred!(c.state(), [$($parts)*]))
+    };
+    ( [$($parts:tt)*] move $($rest:tt)* ) => {
+        $crate::Step::closure(move |c| $crate::subdeferred!(c.state(), [$($parts)*]))
+    };
+    ( [$($parts:tt)*] $name:literal => $($rest:tt)* ) => {
+        $crate::__deferred_nested!([$($parts)*] $($rest)*)
+    };
+    ( [$($parts:tt)*] [$($nested:tt)*] $(, $($rest:tt)*)? ) => {
+        $crate::__deferred_nested!([$($parts)*] $($nested)* $(, $($rest)*)?)
+    };
+    ( [$($parts:tt)*] $v:expr $(, $($rest:tt)*)? ) => {
+        $crate::__deferred_nested!([$($parts)*] $($($rest)*)?)
     };
 }
 
 #[macro_export]
 macro_rules! state {
     ( $s:expr ) => {
-        crate::Context::State($s)
+        $crate::Context::from_state($s)
     };
 }
 
+/// Creates context holding deferred subroutine. Accepts the same part forms as `deferred!`.
 #[macro_export]
 macro_rules! subdeferred {
-    ( $s:expr, [$($v:expr),*] ) => {
-        crate::Context::Deferred(Box::new(crate::Deferred::new($s, vec![$($v,)*])))
+    ( $s:expr, [$($parts:tt)*] ) => {
+        $crate::Context::from_deferred($crate::deferred!($s, [$($parts)*]))
     };
     ( $s:expr ) => {
-        crate::Context::Deferred(Box::new(crate::Deferred::new($s, vec![])))
+        $crate::Context::from_deferred($crate::deferred!($s))
+    };
+}
+
+/// Creates context of fallible part, where state is `Result`: on `Ok` value is passed to given
+/// expression that produces new `Result`, while `Err` is passed further unchanged so remaining
+/// fallible parts are skipped.
+///
+/// # Example
+/// ```
+/// # #[macro_use] extern crate deferred;
+/// # use deferred::*;
+/// # fn main() {
+/// fn parse(v: &str) -> Deferred<Result<String, String>> {
+///     deferred!(Ok(v.to_owned()), [
+///         |c| try_state!(c.state(), |v| v.parse::<i32>().map(|v| v.to_string()).map_err(|_| v)),
+///         |c| try_state!(c.state(), |v| Ok(format!("{}!", v))),
+///     ])
+/// }
+///
+/// assert_eq!(parse("42").consume(), Ok("42!".to_owned()));
+/// assert_eq!(parse("foo").consume(), Err("foo".to_owned()));
+/// # }
+/// ```
+#[macro_export]
+macro_rules! try_state {
+    ( $s:expr, |$v:ident| $e:expr ) => {
+        $crate::Context::from_state(match $s {
+            Ok($v) => $e,
+            Err(error) => Err(error),
+        })
     };
 }
 
 #[macro_export]
 macro_rules! value {
     ( $v:expr ) => {
-        crate::value::Value::new(Box::new($v))
+        $crate::value::Value::from_box($crate::__private::Box::new($v))
+    };
+}
+
+#[macro_export]
+macro_rules! send_value {
+    ( $v:expr ) => {
+        $crate::value::SendValue::from_box($crate::__private::Box::new($v))
+    };
+}
+
+#[macro_export]
+macro_rules! blackboard {
+    ( $($k:expr => $v:expr),+ $(,)? ) => {
+        $crate::blackboard::Blackboard::new()$(.with_key(&$k, $v))+
+    };
+    ( $($v:expr),* $(,)? ) => {
+        $crate::blackboard::Blackboard::new()$(.with($v))*
+    };
+}
+
+/// Marks yield point of function with `#[deferred]` attribute (`deferred-macros` crate, enabled
+/// with `macros` feature). It cannot be used anywhere else.
+#[macro_export]
+macro_rules! yield_now {
+    () => {
+        compile_error!("`yield_now!()` can be used only in functions with `#[deferred]` attribute")
     };
 }
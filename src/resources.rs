@@ -0,0 +1,144 @@
+use crate::lock::{Guard, Lock, Ptr};
+use alloc::{boxed::Box, collections::BTreeMap};
+use core::any::{Any, TypeId};
+
+type Cell = Ptr<Lock<Box<dyn Any + Send>>>;
+
+/// Shared typed resources (like logger, random generator or game world) that parts can access
+/// without storing them in state, got from `Context::resources()`.
+///
+/// `DeferredManager` owns resources and lends them to parts of every unit it runs for the duration
+/// of their call, so units do not store them. Every resource is stored in its own cell, so
+/// different resources can be borrowed at the same time, while borrowing the same resource twice
+/// at once (or taking resource that is borrowed) panics. Resources have to be `Send`, so manager
+/// can be moved to other thread together with them.
+///
+/// # Example
+/// ```
+/// # #[macro_use] extern crate deferred;
+/// # use deferred::*;
+/// # fn main() {
+/// struct Logger(Vec<String>);
+///
+/// let mut manager = DeferredManager::new();
+/// manager.resources().insert(Logger(vec![]));
+/// manager.resources().insert(10);
+/// manager.run(deferred!(1, [
+///     |c| {
+///         let bonus = c.resource(|bonus: &i32| *bonus).unwrap();
+///         c.resource_mut(|logger: &mut Logger| logger.0.push(format!("bonus: {}", bonus)));
+///         state!(c.state() + bonus)
+///     }
+/// ]));
+/// manager.resume_all();
+/// let logger = manager.resources().take::<Logger>().unwrap();
+/// assert_eq!(logger.0, vec!["bonus: 10".to_owned()]);
+/// # }
+/// ```
+#[derive(Default, Clone)]
+pub struct Resources {
+    cells: Ptr<Lock<BTreeMap<TypeId, Cell>>>,
+}
+
+impl Resources {
+    /// Creates new empty resources.
+    #[inline]
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Gets number of stored resources.
+    #[inline]
+    pub fn len(&self) -> usize {
+        self.cells.lock().len()
+    }
+
+    /// Tells if there are no resources.
+    #[inline]
+    pub fn is_empty(&self) -> bool {
+        self.cells.lock().is_empty()
+    }
+
+    /// Inserts resource and returns previous one of the same type.
+    ///
+    /// # Arguments
+    /// * `resource` - resource.
+    ///
+    /// # Panics
+    /// * when resource of the same type is borrowed.
+    pub fn insert<T: Send + 'static>(&self, resource: T) -> Option<T> {
+        if let Some(cell) = self.cell::<T>() {
+            let previous = core::mem::replace(&mut *borrow(&cell), Box::new(resource));
+            return previous.downcast().ok().map(|previous| *previous);
+        }
+        self.cells
+            .lock()
+            .insert(TypeId::of::<T>(), Ptr::new(Lock::new(Box::new(resource))));
+        None
+    }
+
+    /// Tells if there is resource of given type.
+    pub fn has<T: 'static>(&self) -> bool {
+        self.cells.lock().contains_key(&TypeId::of::<T>())
+    }
+
+    /// Calls closure with resource of given type and returns its result, or `None` when there is
+    /// no such resource.
+    ///
+    /// # Arguments
+    /// * `f` - closure getting resource.
+    ///
+    /// # Panics
+    /// * when resource is borrowed.
+    pub fn with<T, R, F>(&self, f: F) -> Option<R>
+    where
+        T: 'static,
+        F: FnOnce(&T) -> R,
+    {
+        let cell = self.cell::<T>()?;
+        let resource = borrow(&cell);
+        resource.downcast_ref::<T>().map(f)
+    }
+
+    /// Calls closure with mutable resource of given type and returns its result, or `None` when
+    /// there is no such resource.
+    ///
+    /// # Arguments
+    /// * `f` - closure getting mutable resource.
+    ///
+    /// # Panics
+    /// * when resource is borrowed.
+    pub fn with_mut<T, R, F>(&self, f: F) -> Option<R>
+    where
+        T: 'static,
+        F: FnOnce(&mut T) -> R,
+    {
+        let cell = self.cell::<T>()?;
+        let mut resource = borrow(&cell);
+        resource.downcast_mut::<T>().map(f)
+    }
+
+    /// Removes resource of given type and returns it.
+    ///
+    /// # Panics
+    /// * when resource is borrowed.
+    pub fn take<T: 'static>(&self) -> Option<T> {
+        let mut cells = self.cells.lock();
+        let id = TypeId::of::<T>();
+        if Ptr::strong_count(cells.get(&id)?) > 1 {
+            panic!("Trying to take resource that is borrowed");
+        }
+        let cell = cells.remove(&id)?;
+        let resource = Ptr::try_unwrap(cell).ok()?.into_inner();
+        resource.downcast().ok().map(|resource| *resource)
+    }
+
+    fn cell<T: 'static>(&self) -> Option<Cell> {
+        self.cells.lock().get(&TypeId::of::<T>()).cloned()
+    }
+}
+
+fn borrow(cell: &Cell) -> Guard<'_, Box<dyn Any + Send>> {
+    cell.try_lock()
+        .expect("Trying to borrow resource that is already borrowed")
+}
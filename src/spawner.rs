@@ -0,0 +1,105 @@
+use crate::deferred::*;
+use crate::deferred_manager::Id;
+use crate::lock::{Lock, Ptr};
+use alloc::vec::Vec;
+use core::sync::atomic::{AtomicUsize, Ordering};
+
+/// Spawned unit with its id and id of its parent.
+pub(crate) type Spawned<S> = (Id, Option<Id>, Deferred<S>);
+
+struct Shared<S> {
+    id_generator: AtomicUsize,
+    pending: Lock<Vec<Spawned<S>>>,
+}
+
+/// Handle used to start new units in `DeferredManager` from inside of running part, got from
+/// `Context::spawner()`.
+///
+/// Manager is mutably borrowed while it resumes units, so spawned units are only queued here and
+/// get registered right after currently executed operation of manager returns (units spawned
+/// outside of it get registered by the next one). Their ids are known right away though, so they
+/// can be stored in state and waited for later.
+///
+/// # Example
+/// ```
+/// # #[macro_use] extern crate deferred;
+/// # use deferred::*;
+/// # fn main() {
+/// let mut manager = DeferredManager::new();
+/// let id = manager.run(deferred!(None, [
+///     |c| {
+///         let child = c.spawner().map(|spawner| {
+///             spawner.run(deferred!(Some(100), [|c| state!(c.state())]))
+///         });
+///         state!(child)
+///     },
+///     |c| state!(c.state())
+/// ]));
+/// assert!(manager.resume(id));
+/// let child = manager.state(id).cloned().unwrap().unwrap();
+/// assert_eq!(manager.state(child), Some(&Some(100)));
+/// # }
+/// ```
+pub struct Spawner<S> {
+    shared: Ptr<Shared<S>>,
+}
+
+impl<S> Spawner<S> {
+    pub(crate) fn new() -> Self {
+        Self {
+            shared: Ptr::new(Shared {
+                id_generator: AtomicUsize::new(0),
+                pending: Lock::new(Vec::new()),
+            }),
+        }
+    }
+
+    /// Queues deferred execution unit to be registered as detached sibling of the one that is
+    /// running and returns id it gets.
+    ///
+    /// # Arguments
+    /// * `deferred` - deferred execution unit.
+    pub fn run(&self, deferred: Deferred<S>) -> Id {
+        let id = self.next_id();
+        self.shared.pending.lock().push((id, None, deferred));
+        id
+    }
+
+    /// Queues deferred execution unit to be registered as child of unit with given id and
+    /// returns id it gets. Child gets cancelled when its parent is already gone by the time child
+    /// gets registered.
+    ///
+    /// # Arguments
+    /// * `parent` - id of parent unit.
+    /// * `deferred` - deferred execution unit.
+    pub fn run_child(&self, parent: Id, deferred: Deferred<S>) -> Id {
+        let id = self.next_id();
+        self.shared
+            .pending
+            .lock()
+            .push((id, Some(parent), deferred));
+        id
+    }
+
+    /// Gets number of spawned units waiting to be registered.
+    #[inline]
+    pub fn pending_count(&self) -> usize {
+        self.shared.pending.lock().len()
+    }
+
+    pub(crate) fn next_id(&self) -> Id {
+        self.shared.id_generator.fetch_add(1, Ordering::Relaxed)
+    }
+
+    pub(crate) fn take_pending(&self) -> Vec<Spawned<S>> {
+        core::mem::take(&mut *self.shared.pending.lock())
+    }
+}
+
+impl<S> Clone for Spawner<S> {
+    fn clone(&self) -> Self {
+        Self {
+            shared: self.shared.clone(),
+        }
+    }
+}
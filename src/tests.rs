@@ -1,5 +1,9 @@
 #![cfg(test)]
+#[cfg(not(feature = "std"))]
+extern crate std;
+
 use crate::*;
+use std::prelude::v1::*;
 
 #[test]
 fn test_resume_consume() {
@@ -264,3 +268,1481 @@ fn test_manager_value() {
         assert_eq!(status2.get(), true);
     }
 }
+
+#[test]
+fn test_any_manager() {
+    fn foo(v: i32) -> Deferred<i32> {
+        deferred!(v, [|c| state!(c.state() + 1), |c| state!(c.state() + 2)])
+    }
+
+    fn bar(v: &str) -> Deferred<String> {
+        deferred!(v.to_owned(), [|c| state!(format!("{}!", c.state()))])
+    }
+
+    let mut manager = AnyDeferredManager::new();
+    {
+        let a = manager.run(foo(1));
+        let b = manager.run(bar("hello"));
+        assert_eq!(manager.count(), 2);
+        assert!(manager.has(a.id()));
+        assert!(manager.has(b.id()));
+
+        manager.resume_all();
+        assert_eq!(manager.count(), 1);
+        assert_eq!(manager.state(a), Some(&2));
+        assert!(!manager.has(b.id()));
+        assert!(manager.is_finished(b));
+        assert_eq!(manager.state(b).map(String::as_str), Some("hello!"));
+
+        manager.resume_all();
+        assert_eq!(manager.count(), 0);
+        assert_eq!(manager.take(a), Some(4));
+        assert_eq!(manager.take(b), Some("hello!".to_owned()));
+        assert_eq!(manager.take(a), None);
+    }
+    {
+        let a = manager.run(foo(1));
+        let b = manager.run(foo(10));
+        let c = manager.run(bar("hi"));
+        let d = manager.run(deferred!(7));
+        assert!(manager.resume(a.id()));
+        assert!(manager.resume(b.id()));
+        assert_eq!(manager.state(a), Some(&2));
+        assert_eq!(manager.state(b), Some(&11));
+        assert!(!manager.is_finished(c));
+        manager.resume_all();
+        assert!(manager.is_finished(c));
+        assert!(manager.is_finished(a));
+        assert!(manager.is_finished(d));
+        assert_eq!(manager.state(d), Some(&7));
+        assert_eq!(manager.consume(b), Some(13));
+
+        let states = manager.consume_all();
+        assert_eq!(states.len(), 3);
+        assert_eq!(states[0].0, a.id());
+        assert_eq!(*states[0].1.unwrap::<i32>(), 4);
+        assert_eq!(states[1].0, c.id());
+        assert_eq!(states[1].1.unwrap::<String>(), "hi!");
+        assert_eq!(states[2].0, d.id());
+        assert_eq!(*states[2].1.unwrap::<i32>(), 7);
+    }
+    {
+        let a = manager.run(foo(1));
+        let b = manager.run(foo(10));
+        let c = manager.run(bar("hi"));
+        assert_eq!(manager.resume_budget(2), 2);
+        assert_eq!(manager.state(a), Some(&2));
+        assert_eq!(manager.state(b), Some(&11));
+        assert!(!manager.is_finished(c));
+        assert_eq!(manager.resume_budget(2), 2);
+        assert!(manager.is_finished(c));
+        assert_eq!(manager.state(a), Some(&4));
+        assert_eq!(manager.state(b), Some(&11));
+        assert_eq!(manager.resume_budget(2), 1);
+        assert!(manager.is_finished(b));
+        assert_eq!(manager.count(), 0);
+        assert_eq!(manager.resume_budget(2), 0);
+    }
+}
+
+#[test]
+fn test_deep_nesting() {
+    const DEPTH: i32 = 100_000;
+
+    fn countdown(v: i32) -> Deferred<i32> {
+        deferred!(
+            v,
+            [
+                |c| {
+                    let v = c.state();
+                    if v > 0 {
+                        countdown(v - 1).into()
+                    } else {
+                        state!(v)
+                    }
+                },
+                |c| state!(c.state() + 1)
+            ]
+        )
+    }
+
+    {
+        let mut d = countdown(DEPTH);
+        let mut steps = 0;
+        while d.can_resume() {
+            d = d.resume().unwrap();
+            steps += 1;
+        }
+        assert_eq!(steps, DEPTH + 2);
+        assert_eq!(d.state(), Some(&(DEPTH + 1)));
+    }
+    {
+        let d = countdown(DEPTH).resume().unwrap();
+        let c: Context<i32> = d.into();
+        assert_eq!(c.get_state(), Some(&0));
+        assert_eq!(c.state(), DEPTH + 1);
+    }
+    {
+        let d = countdown(DEPTH);
+        assert_eq!(d.consume(), DEPTH + 1);
+    }
+}
+
+#[test]
+fn test_static_subroutine() {
+    struct Scale;
+
+    impl StaticSubroutine<i32> for Scale {
+        const PARTS: &'static [Part<i32>] = &[|c| state!(c.state() * 2), |c| state!(c.state() * 3)];
+    }
+
+    fn foo(v: i32) -> Deferred<i32> {
+        deferred!(
+            v,
+            [
+                |c| Context::from_static::<Scale>(c.state()),
+                |c| state!(c.state() + 1)
+            ]
+        )
+    }
+
+    {
+        let d = foo(1).resume().unwrap();
+        assert_eq!(d.state(), Some(&2));
+        let d = d.resume().unwrap();
+        assert_eq!(d.state(), Some(&6));
+        assert_eq!(d.consume(), 7);
+    }
+    {
+        let c = Context::from_static::<Scale>(2);
+        assert!(c.is_deferred());
+        assert_eq!(c.get_state(), Some(&2));
+        assert!(c.get_deferred().is_none());
+        assert_eq!(c.state(), 12);
+    }
+    {
+        let mut d: ArrayDeferred<i32, 2> = ArrayDeferred::new(1, &[|c| state!(c.state() + 1)]);
+        d = d.resume().unwrap();
+        assert_eq!(d.consume(), 2);
+        let d: ArrayDeferred<i32, 2> =
+            ArrayDeferred::new(1, &[|c| Context::from_static::<Scale>(c.state())]);
+        assert_eq!(d.consume(), 6);
+    }
+}
+
+#[test]
+fn test_value_checked() {
+    #[derive(Debug, PartialEq)]
+    struct NotClone(i32);
+
+    let v = value!(NotClone(42));
+    assert!(v.type_name().ends_with("NotClone"));
+    assert!(format!("{:?}", v).contains("NotClone"));
+    let error = v.try_unwrap::<i32>().unwrap_err();
+    assert_eq!(error.expected, "i32");
+    assert_eq!(error.found, v.type_name());
+    let v = v.take::<i32>().unwrap_err();
+    assert_eq!(v.take::<NotClone>().unwrap(), NotClone(42));
+
+    let mut v = send_value!(1u8);
+    *v.unwrap_mut::<u8>() += 1;
+    assert!(v.try_unwrap_mut::<i8>().is_err());
+    let v = Value::from(v);
+    assert_eq!(v.type_name(), "u8");
+    assert_eq!(v.try_consume::<u8>(), Ok(2));
+
+    let v = Value::new(Box::new(1u8));
+    assert_eq!(v.type_name(), "dyn core::any::Any");
+    assert_eq!(v.consume::<u8>(), 1);
+}
+
+#[test]
+#[should_panic(expected = "Expected value of type `u8` but it stores `i32`")]
+fn test_value_mismatch() {
+    value!(42).consume::<u8>();
+}
+
+#[test]
+fn test_blackboard() {
+    const NAME: Key<String> = Key::new("name");
+    const TITLE: Key<String> = Key::new("title");
+
+    fn foo(name: &str) -> Deferred<Blackboard> {
+        deferred!(
+            blackboard!(NAME => name.to_owned(), TITLE => "Sir".to_owned()),
+            [
+                |c| {
+                    let mut b = c.state();
+                    b.insert(0usize);
+                    state!(b)
+                },
+                |c| foo2(c.state().scope()).into(),
+                |c| {
+                    let mut b = c.state().unscope();
+                    assert!(b.parent().is_none());
+                    let greeting = b.take::<String>().unwrap();
+                    b.insert_value(value!(greeting.len()));
+                    state!(b)
+                }
+            ]
+        )
+    }
+
+    fn foo2(b: Blackboard) -> Deferred<Blackboard> {
+        deferred!(
+            b,
+            [|c| {
+                let mut b = c.state();
+                assert!(b.is_empty());
+                *b.get_mut::<usize>().unwrap() += 1;
+                let greeting = format!(
+                    "Hello, {} {}!",
+                    b.get_key(&TITLE).unwrap(),
+                    b.get_key(&NAME).unwrap()
+                );
+                assert_eq!(b.insert(greeting), None);
+                state!(b)
+            }]
+        )
+    }
+
+    let d = foo("Lancelot").resume().unwrap().resume().unwrap();
+    let c: Context<Blackboard> = d.into();
+    {
+        let b = c.get_state().unwrap();
+        assert_eq!(b.len(), 1);
+        assert_eq!(b.get::<usize>(), Some(&1));
+        assert_eq!(b.get::<String>().unwrap(), "Hello, Sir Lancelot!");
+        assert_eq!(b.parent().unwrap().len(), 3);
+    }
+    let mut b = c.state();
+    assert_eq!(b.take::<usize>(), Some(20));
+    assert_eq!(b.take_key(&NAME).unwrap(), "Lancelot");
+    assert!(b.has_key(&TITLE));
+    assert!(!b.has::<String>());
+}
+
+#[test]
+fn test_builder() {
+    fn sub(v: Vec<String>) -> Deferred<Vec<String>> {
+        deferred!(
+            v,
+            [|c| {
+                let mut v = c.state();
+                v.push("sub".to_owned());
+                state!(v)
+            }]
+        )
+    }
+
+    let ops = ["a", "b", "c"];
+    let mut builder = DeferredBuilder::new(vec![]);
+    for op in ops.iter() {
+        let op = op.to_string();
+        builder = builder.then(move |c| {
+            let mut v = c.state();
+            v.push(op);
+            state!(v)
+        });
+    }
+    let builder = builder
+        .then_sub(sub)
+        .then_part(|c| {
+            let mut v = c.state();
+            v.push("part".to_owned());
+            state!(v)
+        })
+        .prepend(|c| {
+            let mut v = c.state();
+            v.push("first".to_owned());
+            state!(v)
+        })
+        .prepend_part(|c| {
+            let mut v = c.state();
+            v.push("zero".to_owned());
+            state!(v)
+        })
+        .insert_at(
+            2,
+            Step::closure(|c: Context<Vec<String>>| {
+                let mut v = c.state();
+                v.push("inserted".to_owned());
+                state!(v)
+            })
+            .named("inserted"),
+        );
+    assert_eq!(builder.len(), 8);
+
+    let mut d = builder.build().resume().unwrap().resume().unwrap();
+    assert_eq!(d.next_part_name(), Some("inserted"));
+    d.push(|c| {
+        let mut v = c.state();
+        v.push("last".to_owned());
+        state!(v)
+    });
+    d.extend(vec![Step::closure(|c: Context<Vec<String>>| {
+        let mut v = c.state();
+        v.push("extended".to_owned());
+        state!(v)
+    })]);
+    assert_eq!(
+        d.consume(),
+        vec!["zero", "first", "inserted", "a", "b", "c", "sub", "part", "last", "extended"]
+    );
+}
+
+#[test]
+fn test_combinators() {
+    fn count(v: usize, n: usize) -> Deferred<usize> {
+        DeferredBuilder::new(v)
+            .extend((0..n).map(|_| Step::new(|c| state!(c.state() + 1))))
+            .build()
+    }
+
+    let steps = std::sync::Arc::new(std::sync::atomic::AtomicUsize::new(0));
+    let steps2 = steps.clone();
+    let d = count(0, 2)
+        .then(count(10, 1))
+        .and_then(|v| count(v * 2, 2))
+        .map(|v| v + 100)
+        .inspect(move |_| {
+            steps2.fetch_add(1, std::sync::atomic::Ordering::Relaxed);
+        });
+    assert_eq!(d.consume(), 124);
+    assert_eq!(steps.load(std::sync::atomic::Ordering::Relaxed), 6);
+
+    let mut d = count(0, 3).zip(count(0, 1).and_then(|v| count(v, 1)));
+    let mut resumes = 0;
+    while d.can_resume() {
+        d = d.resume().unwrap();
+        resumes += 1;
+    }
+    assert_eq!(resumes, 3);
+    assert_eq!(d.consume().into_states(), (3, 2));
+
+    let steps2 = steps.clone();
+    steps.store(0, std::sync::atomic::Ordering::Relaxed);
+    let d = count(0, 4).zip(count(0, 2)).inspect(move |_| {
+        steps2.fetch_add(1, std::sync::atomic::Ordering::Relaxed);
+    });
+    assert_eq!(d.consume().into_states(), (4, 2));
+    assert_eq!(steps.load(std::sync::atomic::Ordering::Relaxed), 4);
+
+    let d = count(5, 0).zip(count(6, 0));
+    assert!(!d.can_resume());
+    assert_eq!(d.consume().into_states(), (5, 6));
+}
+
+#[test]
+fn test_send() {
+    fn is_send<T: Send>() {}
+
+    is_send::<Deferred<i32>>();
+    is_send::<Step<String>>();
+    is_send::<Deferred<Zip<i32, String>>>();
+    is_send::<DeferredManager<i32>>();
+    is_send::<JoinHandle<i32>>();
+    is_send::<Spawner<i32>>();
+    is_send::<Resources>();
+
+    let n = 3;
+    let d = deferred!(
+        1,
+        [
+            [|c| state!(c.state() + 1), [|c| state!(c.state() * 2)]],
+            move |c| state!(c.state() * n).emit(n),
+        ]
+    )
+    .then(deferred!(0, [|c| state!(c.state() + 1)]))
+    .map(move |v| v + n)
+    .inspect(|_| {})
+    .on_cancel(|_| {});
+    let mut d = std::thread::spawn(move || d.resume_n(3)).join().unwrap();
+    assert_eq!(d.state(), Some(&12));
+    assert_eq!(d.drain_emitted().next().unwrap().consume::<i32>(), 3);
+    assert_eq!(d.consume(), 4);
+}
+
+#[test]
+fn test_debug_deferred() {
+    fn foo(v: i32) -> Deferred<i32> {
+        deferred!(v, [
+            |c| state!(c.state() + 1),
+            |c| foo2(c.state()).into(),
+            "last" => |c| state!(c.state() + 2),
+        ])
+    }
+
+    fn foo2(v: i32) -> Deferred<i32> {
+        deferred!(v, [
+            "double" => |c| state!(c.state() * 2),
+            |c| state!(c.state() * 3),
+        ])
+    }
+
+    let mut d = DebugDeferred::new(foo(1));
+    d.add_breakpoint(Breakpoint::Index(3));
+    d.add_breakpoint(Breakpoint::Name("last"));
+    assert_eq!(d.run(), Stop::Breakpoint(Breakpoint::Index(3)));
+    assert_eq!(d.breakpoint(), Some(Breakpoint::Index(3)));
+    assert_eq!(d.steps(), 3);
+    assert_eq!(d.state(), Some(&12));
+    assert!(d.remove_breakpoint(Breakpoint::Name("last")));
+    assert_eq!(d.run(), Stop::Completed);
+    assert!(!d.step());
+    assert_eq!(d.state(), Some(&14));
+
+    let recording = d.recording();
+    let parts = recording
+        .records()
+        .iter()
+        .map(|r| (r.index, r.part))
+        .collect::<Vec<_>>();
+    assert_eq!(
+        parts,
+        vec![
+            (0, None),
+            (1, None),
+            (2, Some("double")),
+            (3, None),
+            (4, Some("last")),
+        ]
+    );
+    assert_eq!(recording.diff(recording), None);
+    let other = DebugDeferred::new(foo(1).map(|v| v + 1)).record();
+    let divergence = recording.diff(&other).unwrap();
+    assert_eq!(divergence.index, 5);
+    assert!(divergence.left.is_none());
+    assert_eq!(divergence.right.unwrap().state, 15);
+
+    fn outer(v: i32) -> Deferred<i32> {
+        deferred!(v, [|c| foo(c.state()).into(), |c| state!(c.state() - 1)])
+    }
+
+    let mut d = DebugDeferred::new(outer(1));
+    d.add_breakpoint(Breakpoint::Name("double"));
+    d.add_breakpoint(Breakpoint::Name("last"));
+    assert_eq!(d.run(), Stop::Breakpoint(Breakpoint::Name("double")));
+    assert_eq!(d.steps(), 1);
+    assert_eq!(d.state(), Some(&2));
+    assert_eq!(d.run(), Stop::Breakpoint(Breakpoint::Name("last")));
+    assert_eq!(d.state(), Some(&12));
+    assert_eq!(d.run(), Stop::Completed);
+    let parts = d
+        .recording()
+        .records()
+        .iter()
+        .map(|r| (r.part, r.state))
+        .collect::<Vec<_>>();
+    assert_eq!(
+        parts,
+        vec![
+            (None, 1),
+            (None, 2),
+            (Some("double"), 4),
+            (None, 12),
+            (Some("last"), 14),
+            (None, 13),
+        ]
+    );
+
+    fn first(v: i32) -> Deferred<i32> {
+        deferred!(v, ["first" => |c| state!(c.state() + 1), |c| state!(c.state() * 2)])
+    }
+
+    let mut d = DebugDeferred::new(first(1));
+    d.add_breakpoint(Breakpoint::Name("first"));
+    d.add_breakpoint(Breakpoint::Index(0));
+    assert_eq!(d.run(), Stop::Breakpoint(Breakpoint::Name("first")));
+    assert_eq!(d.steps(), 0);
+    assert_eq!(d.state(), Some(&1));
+    assert_eq!(d.run(), Stop::Completed);
+    assert_eq!(d.steps(), 2);
+    assert_eq!(d.state(), Some(&4));
+
+    let mut d = DebugDeferred::new(first(1));
+    d.add_breakpoint(Breakpoint::Index(0));
+    assert_eq!(d.run(), Stop::Breakpoint(Breakpoint::Index(0)));
+    assert_eq!(d.steps(), 0);
+    assert!(d.step());
+    assert_eq!(d.run(), Stop::Completed);
+    assert_eq!(d.state(), Some(&4));
+}
+
+#[test]
+fn test_batch_resume() {
+    fn count(n: usize) -> Deferred<usize> {
+        DeferredBuilder::new(0)
+            .extend((0..n).map(|_| Step::new(|c| state!(c.state() + 1))))
+            .build()
+    }
+
+    let d = count(10).resume_n(0);
+    assert_eq!(d.state(), Some(&0));
+    let d = d
+        .resume_while(|v| *v < 4)
+        .resume_until_state(|v| v % 3 == 0);
+    assert_eq!(d.state(), Some(&6));
+    let d = d.resume_while(|_| true);
+    assert_eq!(d.state(), Some(&10));
+    assert!(!d.can_resume());
+
+    let mut manager = DeferredManager::new();
+    let a = manager.run(count(5));
+    let b = manager.run(count(5));
+    assert_eq!(manager.resume_while(a, |v| *v < 3), 3);
+    assert_eq!(manager.resume_until_state(b, |v| *v == 4), 4);
+    assert_eq!(manager.resume_until_state(a, |v| *v == 100), 2);
+    assert!(!manager.has(a));
+    assert_eq!(manager.resume_n(a, 1), 0);
+    assert_eq!(manager.resume_n(b, 3), 1);
+    assert_eq!(manager.count(), 0);
+}
+
+#[test]
+#[cfg(feature = "std")]
+fn test_resume_in_place() {
+    let mut d = deferred!(
+        0,
+        [
+            |c| state!(c.state() + 1),
+            |c| -> Context<i32> { panic!("failed with {}", c.state()) },
+            |c| state!(c.state() + 1),
+        ]
+    );
+    assert_eq!(d.resume_in_place(), Status::Progressed);
+    *d.state_mut().unwrap() += 10;
+    let result = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| {
+        d.resume_in_place_preserving()
+    }));
+    assert!(result.is_err());
+    assert_eq!(d.state(), Some(&11));
+    assert_eq!(d.remaining_parts(), 1);
+    assert!(d.can_resume());
+    assert_eq!(d.resume_in_place_preserving(), Status::Completed);
+    assert_eq!(d.state(), Some(&12));
+    assert_eq!(d.resume_in_place_preserving(), Status::Idle);
+
+    let mut d = deferred!(
+        0,
+        [
+            |c| deferred!(
+                c.state() + 1,
+                [|c| -> Context<i32> { panic!("{}", c.state()) }]
+            )
+            .into(),
+            |c| state!(c.state() + 1),
+        ]
+    );
+    let result = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| d.resume_in_place()));
+    assert!(result.is_err());
+    assert_eq!(d.state(), None);
+    assert!(!d.can_resume());
+    assert_eq!(d.resume_in_place(), Status::Idle);
+
+    let token = std::sync::Arc::new(());
+    let observed = token.clone();
+    let mut d = deferred!(
+        0,
+        [
+            |c| state!(c.state() + 1),
+            |c| -> Context<i32> { panic!("failed with {}", c.state()) },
+            |c| state!(c.state() + 1),
+        ]
+    )
+    .inspect(move |_| {
+        let _ = &observed;
+    });
+    assert_eq!(d.resume_in_place_preserving(), Status::Progressed);
+    let result = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| {
+        d.resume_in_place_preserving()
+    }));
+    assert!(result.is_err());
+    assert_eq!(std::sync::Arc::strong_count(&token), 2);
+    assert_eq!(d.resume_in_place_preserving(), Status::Completed);
+    assert_eq!(std::sync::Arc::strong_count(&token), 1);
+}
+
+#[test]
+#[cfg(feature = "std")]
+fn test_manager_lost_state() {
+    fn lost() -> Deferred<i32> {
+        deferred!(0, [|_| panic!("lost"), |c| state!(c.state() + 1)])
+    }
+
+    let failure = Failure::Panic("Deferred execution has lost its state".to_owned());
+    let mut manager = DeferredManager::new();
+    let handle = manager.spawn(lost());
+    let result = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| manager.resume_all()));
+    assert!(result.is_err());
+    manager.resume_all();
+    manager.resume_all();
+    assert_eq!(
+        handle.outcome().as_deref(),
+        Some(&Outcome::Failed(failure.clone()))
+    );
+    assert_eq!(manager.failure(handle.id()), Some(&failure));
+
+    let id = manager.run(lost());
+    let result = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| manager.resume_all()));
+    assert!(result.is_err());
+    manager.resume_all();
+    assert_eq!(manager.failure(id), Some(&failure));
+    assert_eq!(manager.count(), 0);
+}
+
+#[test]
+#[cfg(feature = "std")]
+fn test_manager_panic_isolation() {
+    fn foo(v: i32, fail_at: i32) -> Deferred<i32> {
+        DeferredBuilder::new(v)
+            .extend((0..3).map(|_| {
+                Step::new(|c| {
+                    let v = c.state();
+                    if v < 0 {
+                        panic!("negative {}", v);
+                    }
+                    state!(v - 1)
+                })
+            }))
+            .build()
+            .map(move |v| if v == fail_at { panic!("{}", v) } else { v })
+    }
+
+    let mut manager = DeferredManager::new();
+    manager.set_isolate_panics(true);
+    assert!(manager.isolates_panics());
+    let a = manager.run(foo(1, 100));
+    let b = manager.run(foo(10, 100));
+    let c = manager.run(foo(10, 7));
+    let d = manager.run(foo(10, 7));
+    manager.resume_all();
+    manager.resume_all();
+    manager.resume_all();
+    assert_eq!(
+        manager.failure(a),
+        Some(&Failure::Panic("negative -1".to_owned()))
+    );
+    assert!(manager.has(b));
+    assert_eq!(manager.resume_n(c, 10), 0);
+    assert!(!manager.has(c));
+    assert_eq!(manager.consume(d), None);
+    assert_eq!(manager.consume_all(), vec![(b, 7)]);
+    assert_eq!(
+        manager.take_failures(),
+        vec![
+            (a, Failure::Panic("negative -1".to_owned())),
+            (c, Failure::Panic("7".to_owned())),
+            (d, Failure::Panic("7".to_owned())),
+        ]
+    );
+    assert_eq!(
+        Failure::Panic("7".to_owned()).to_string(),
+        "Part has panicked: 7"
+    );
+
+    let e = manager.run(foo(1, 100));
+    assert_eq!(manager.resume_n(e, 10), 2);
+    assert!(!manager.has(e));
+    let f = manager.run(foo(10, 7));
+    assert_eq!(manager.resume_while(f, |_| true), 3);
+    assert_eq!(manager.failed_count(), 2);
+}
+
+#[test]
+fn test_limits() {
+    fn tail(c: Context<usize>) -> Context<usize> {
+        let v = c.state() + 1;
+        if v < 1000 {
+            deferred!(v, [tail]).into()
+        } else {
+            state!(v)
+        }
+    }
+
+    fn nested(c: Context<usize>) -> Context<usize> {
+        deferred!(c.state() + 1, [nested, |c| state!(c.state())]).into()
+    }
+
+    fn wide(c: Context<usize>) -> Context<usize> {
+        let v = c.state() + 1;
+        DeferredBuilder::new(v)
+            .then(wide)
+            .extend((0..v).map(|_| Step::new(|c| c)))
+            .build()
+            .into()
+    }
+
+    let depth = Limits {
+        max_depth: Some(3),
+        ..Default::default()
+    };
+    let d = deferred!(0, [tail]).with_limits(depth).resume().unwrap();
+    assert_eq!(d.limit_exceeded(), None);
+    assert_eq!(d.state(), Some(&1000));
+    assert_eq!(d.limits(), Some(&depth));
+
+    let d = deferred!(0, [nested]).with_limits(depth);
+    assert_eq!(d.consume(), 4);
+
+    let d = deferred!(
+        0,
+        [|c| deferred!(c.state(), [tail])
+            .with_limits(Limits {
+                max_steps: Some(1),
+                ..Default::default()
+            })
+            .into()]
+    );
+    let d = d.resume().unwrap();
+    assert_eq!(d.limit_exceeded(), None);
+    assert_eq!(d.state(), Some(&1000));
+
+    let d = deferred!(0, [tail]).with_limits(Limits {
+        max_steps: Some(10),
+        ..Default::default()
+    });
+    let d = d.resume().unwrap();
+    assert_eq!(d.limit_exceeded(), Some(LimitExceeded::Steps(10)));
+    assert_eq!(d.state(), Some(&10));
+    assert!(!d.can_resume());
+
+    let mut manager = DeferredManager::new();
+    manager.set_default_limits(Some(Limits {
+        max_parts: Some(100),
+        ..Default::default()
+    }));
+    let a = manager.run(deferred!(0, [wide]));
+    let b = manager.run(deferred!(0, [tail]).with_limits(Limits::default()));
+    assert_eq!(manager.consume(a), None);
+    assert_eq!(manager.consume(b), Some(1000));
+    assert_eq!(
+        manager.take_failures(),
+        vec![(a, Failure::Limit(LimitExceeded::Parts(100)))]
+    );
+}
+
+#[test]
+#[cfg(feature = "std")]
+fn test_limits_duration() {
+    fn slow(c: Context<usize>) -> Context<usize> {
+        std::thread::sleep(std::time::Duration::from_millis(5));
+        deferred!(c.state() + 1, [slow]).into()
+    }
+
+    let limit = std::time::Duration::from_millis(20);
+    let mut manager = DeferredManager::new();
+    let id = manager.run(deferred!(0, [slow]).with_limits(Limits {
+        max_duration: Some(limit),
+        ..Default::default()
+    }));
+    manager.resume_all();
+    assert_eq!(
+        manager.failure(id),
+        Some(&Failure::Limit(LimitExceeded::Duration(limit)))
+    );
+}
+
+#[test]
+fn test_limits_duration_clock() {
+    use core::sync::atomic::{AtomicU64, Ordering};
+    use core::time::Duration;
+
+    static NOW: AtomicU64 = AtomicU64::new(0);
+
+    fn slow(c: Context<usize>) -> Context<usize> {
+        NOW.fetch_add(5, Ordering::Relaxed);
+        deferred!(c.state() + 1, [slow]).into()
+    }
+
+    let limit = Duration::from_millis(20);
+    let mut manager = DeferredManager::new();
+    manager.set_clock(Some(|| Duration::from_millis(NOW.load(Ordering::Relaxed))));
+    let id = manager.run(deferred!(0, [slow]).with_limits(Limits {
+        max_duration: Some(limit),
+        ..Default::default()
+    }));
+    manager.resume_all();
+    assert_eq!(
+        manager.failure(id),
+        Some(&Failure::Limit(LimitExceeded::Duration(limit)))
+    );
+    assert_eq!(NOW.load(Ordering::Relaxed), 25);
+}
+
+#[test]
+fn test_manager_query() {
+    let mut manager = DeferredManager::new();
+    manager.extend(
+        (0..3).map(|v| deferred!(v, [|c| state!(c.state() + 1), |c| state!(c.state() + 1),])),
+    );
+    assert_eq!(manager.count(), 3);
+    manager.resume(1);
+    assert_eq!(manager.state(1), Some(&2));
+    assert_eq!(manager.get(1).unwrap().remaining_parts(), 1);
+    manager.get_mut(2).unwrap().push(|c| state!(c.state() * 10));
+    manager.retain(|id, _| id != 0);
+    let mut ids = manager.ids().collect::<Vec<_>>();
+    ids.sort();
+    assert_eq!(ids, vec![1, 2]);
+    for (_, mut d) in manager.iter_mut() {
+        d.resume_in_place();
+    }
+    assert!(!manager.get(1).unwrap().can_resume());
+    assert_eq!(manager.state(1), Some(&3));
+    assert_eq!(manager.consume(2), Some(40));
+    assert_eq!(manager.state_mut(0), None);
+}
+
+#[test]
+fn test_resume_with_input() {
+    fn dialog() -> Deferred<Vec<String>> {
+        deferred!(
+            vec![],
+            [
+                |c| subdeferred!(
+                    c.state(),
+                    [
+                        |c| {
+                            assert!(!c.has_input());
+                            state!(c.state())
+                        },
+                        |mut c| {
+                            let choice = c.take_input::<&str>();
+                            let mut v = c.state();
+                            v.push(format!("inner {:?}", choice));
+                            state!(v)
+                        },
+                    ]
+                ),
+                |mut c| {
+                    assert!(c.has_input());
+                    assert_eq!(c.input::<&str>(), None);
+                    let choice = c.take_input::<usize>();
+                    let mut v = c.state();
+                    v.push(format!("outer {:?}", choice));
+                    state!(v)
+                },
+            ]
+        )
+    }
+
+    let d = dialog().resume_with("lost").unwrap();
+    let d = d.resume_with("yes").unwrap();
+    let d = d.resume_with(2usize).unwrap();
+    assert_eq!(
+        d.consume(),
+        vec!["inner Some(\"yes\")".to_owned(), "outer Some(2)".to_owned()]
+    );
+
+    let mut manager = DeferredManager::new();
+    let id = manager.run(dialog());
+    assert!(manager.resume(id));
+    assert!(manager.resume_with(id, "no"));
+    assert_eq!(manager.state(id).unwrap(), &vec!["inner Some(\"no\")"]);
+    assert!(manager.resume_with(id, 1usize));
+    assert!(!manager.has(id));
+    assert!(!manager.resume_with(id, 1usize));
+}
+
+#[test]
+fn test_generator() {
+    fn chunk(from: usize) -> Deferred<usize> {
+        deferred!(
+            from,
+            [
+                |c| {
+                    let v = c.state();
+                    state!(v + 1).emit(v)
+                },
+                |c| {
+                    let v = c.state();
+                    state!(v + 1).emit(v).emit(format!("{} done", v))
+                },
+            ]
+        )
+    }
+
+    fn search() -> Deferred<usize> {
+        deferred!(
+            0,
+            [
+                |c| chunk(c.state()).into(),
+                |c| state!(c.state() * 10),
+                |c| chunk(c.state()).into(),
+            ]
+        )
+    }
+
+    let items = search()
+        .inspect(|_| {})
+        .filter_map(|item| item.take::<usize>().ok())
+        .collect::<Vec<_>>();
+    assert_eq!(items, vec![0, 1, 20, 21]);
+
+    let mut d = search();
+    let mut d2 = d.by_ref().skip(3);
+    assert_eq!(d2.next().unwrap().consume::<usize>(), 20);
+    assert_eq!(d.state(), Some(&21));
+    assert_eq!(d.emitted_count(), 0);
+
+    let mut manager = DeferredManager::new();
+    let a = manager.run(search());
+    let b = manager.run(chunk(100));
+    manager.resume_all();
+    manager.resume_all();
+    manager.consume_all();
+    let emitted = manager.drain_all_emitted();
+    assert_eq!(emitted.len(), 2);
+    assert_eq!(emitted[0].0, a);
+    assert_eq!(emitted[0].1.len(), 6);
+    assert_eq!(emitted[1].0, b);
+    assert_eq!(emitted[1].1[2].get::<String>().unwrap(), "101 done");
+
+    let a = manager.run(search());
+    let b = manager.run(search());
+    let c = manager.run(
+        deferred!(0, [|c| state!(c.state()).emit(1), |c| state!(c.state())]).with_limits(Limits {
+            max_steps: Some(1),
+            ..Default::default()
+        }),
+    );
+    manager.resume_all();
+    manager.resume_all();
+    manager.cancel(a);
+    manager.retain(|id, _| id != b);
+    assert!(manager.failure(c).is_some());
+    assert!(manager.drain_all_emitted().is_empty());
+}
+
+#[test]
+fn test_events_unit_mut() {
+    fn guard() -> Deferred<usize> {
+        deferred!(
+            0,
+            [
+                |c| state!(c.state() + 1).wait_for("e"),
+                |c| state!(c.state() + 10),
+                |c| state!(c.state() + 100),
+            ]
+        )
+    }
+
+    let mut manager = DeferredManager::new();
+    let a = manager.run(guard());
+    manager.resume_all();
+    assert_eq!(manager.waiting_count("e"), 1);
+    assert!(manager.get_mut(a).unwrap().signal("e"));
+    assert_eq!(manager.waiting_count("e"), 0);
+    assert_eq!(manager.runnable_count(), 1);
+    assert_eq!(manager.signal("e"), 0);
+    manager.resume_all();
+    assert_eq!(manager.state(a), Some(&11));
+
+    let b = manager.run(guard());
+    manager.get_mut(b).unwrap().resume_in_place();
+    assert_eq!(manager.waiting_for(b), Some("e"));
+    assert_eq!(manager.waiting_count("e"), 1);
+    manager.resume_all();
+    assert_eq!(manager.state(b), Some(&1));
+    for (_, mut d) in manager.iter_mut() {
+        d.signal("e");
+    }
+    assert_eq!(manager.waiting_count("e"), 0);
+    manager.resume_all();
+    assert_eq!(manager.state(b), Some(&11));
+}
+
+#[test]
+fn test_events() {
+    fn guard(name: &'static str) -> Deferred<usize> {
+        deferred!(
+            0,
+            [
+                move |c| state!(c.state() + 1).wait_for(name),
+                |c| subdeferred!(
+                    c.state(),
+                    [|c| state!(c.state() + 1).wait_for(format!("{}_again", "alarm")),]
+                ),
+                |c| state!(c.state() + 1),
+            ]
+        )
+    }
+
+    let mut manager = DeferredManager::new();
+    let a = manager.run(guard("alarm"));
+    let b = manager.run(guard("alarm"));
+    let c = manager.run(guard("noise"));
+    let d = manager.run(deferred!(0, [|c| state!(c.state() + 1)]));
+    assert_eq!(manager.runnable_count(), 4);
+    manager.resume_all();
+    assert!(!manager.has(d));
+    assert!(!manager.has_runnable());
+    assert_eq!(manager.count(), 3);
+    assert_eq!(manager.waiting_for(a), Some("alarm"));
+    manager.resume_all();
+    assert_eq!(manager.state(a), Some(&1));
+    assert_eq!(manager.signal("nothing"), 0);
+    assert_eq!(manager.signal("alarm"), 2);
+    assert_eq!(manager.runnable_count(), 2);
+    assert_eq!(manager.resume_n(a, 10), 1);
+    assert_eq!(manager.waiting_for(a), Some("alarm_again"));
+    manager.resume_all();
+    assert_eq!(manager.runnable_count(), 0);
+    assert_eq!(manager.signal("alarm_again"), 2);
+    manager.resume_all();
+    assert!(!manager.has(a));
+    assert!(!manager.has(b));
+    assert_eq!(manager.waiting_count("noise"), 1);
+    assert!(manager.resume(c));
+    assert_eq!(manager.waiting_count("noise"), 0);
+    assert_eq!(manager.waiting_for(c), Some("alarm_again"));
+    assert_eq!(manager.waiting_count("alarm_again"), 1);
+    assert_eq!(manager.consume(c), Some(3));
+    assert_eq!(manager.waiting_count("alarm_again"), 0);
+
+    let e = manager.run(guard("alarm"));
+    let f = manager.run(guard("alarm"));
+    manager.resume_all();
+    manager.resume_all();
+    assert_eq!(manager.waiting_count("alarm"), 2);
+    manager.cancel(e);
+    assert_eq!(manager.waiting_count("alarm"), 1);
+    assert_eq!(manager.signal("alarm"), 1);
+    assert_eq!(manager.resume_n(f, 1), 1);
+    assert_eq!(manager.waiting_count("alarm_again"), 1);
+}
+
+#[test]
+fn test_timers() {
+    let mut manager = DeferredManager::new();
+    manager.advance_time(100);
+    assert_eq!(manager.time(), 100);
+    let now = manager.run_at(50, deferred!(0, [|c| state!(c.state() + 1)]));
+    assert!(manager.has(now));
+    assert!(!manager.is_scheduled(now));
+    let later = manager.run_after_delay(20, deferred!(0, [|c| state!(c.state() + 1)]));
+    let sooner = manager.run_at(110, deferred!(0, [|c| state!(c.state() + 1)]));
+    let job = manager.run_every(15, || deferred!(0, [|c| state!(c.state() + 1)]));
+    assert_eq!(manager.count(), 1);
+    assert_eq!(manager.scheduled_count(), 3);
+    assert_eq!(manager.next_wake_time(), Some(110));
+    manager.set_time(90);
+    assert_eq!(manager.time(), 100);
+    manager.advance_time(10);
+    assert!(manager.has(sooner));
+    assert!(!manager.has(later));
+    assert!(manager.is_scheduled(later));
+    assert_eq!(manager.next_wake_time(), Some(115));
+    manager.advance_time(40);
+    assert!(manager.has(later));
+    assert_eq!(manager.count(), 4);
+    assert_eq!(manager.next_wake_time(), Some(160));
+    assert!(manager.cancel(job));
+    assert!(!manager.is_scheduled(job));
+    assert_eq!(manager.next_wake_time(), None);
+    manager.consume_all();
+    assert_eq!(manager.count(), 0);
+    let cancelled = manager.run_after_delay(5, deferred!(0, [|c| state!(c.state() + 1)]));
+    assert!(manager.cancel(cancelled));
+    assert!(!manager.cancel(cancelled));
+    manager.advance_time(10);
+    assert_eq!(manager.count(), 0);
+
+    manager.set_time(Tick::MAX - 15);
+    let job = manager.run_every(10, || deferred!(0, [|c| state!(c.state() + 1)]));
+    manager.set_time(Tick::MAX);
+    assert_eq!(manager.count(), 1);
+    assert!(!manager.is_scheduled(job));
+    assert_eq!(manager.next_wake_time(), None);
+}
+
+#[test]
+fn test_timers_time_jump() {
+    let mut manager = DeferredManager::new();
+    let job = manager.run_every(10, || deferred!(0, [|c| state!(c.state() + 1)]));
+    manager.set_time(Tick::MAX / 2 + 5);
+    assert_eq!(manager.count(), 1);
+    assert_eq!(manager.next_wake_time(), Some(Tick::MAX / 2 + 13));
+    manager.set_time(Tick::MAX - 3);
+    assert_eq!(manager.count(), 2);
+    assert!(!manager.is_scheduled(job));
+    assert_eq!(manager.next_wake_time(), None);
+}
+
+#[test]
+fn test_spawner() {
+    fn parent(c: Context<Vec<usize>>) -> Context<Vec<usize>> {
+        let spawner = c.spawner().unwrap().clone();
+        let mut children = c.state();
+        for v in 0..2 {
+            children.push(spawner.run(deferred!(vec![v], [|c| state!(c.state())])));
+        }
+        state!(children)
+    }
+
+    let mut manager = DeferredManager::new();
+    let id = manager.run(deferred!(vec![], [parent, |c| state!(c.state())]));
+    manager.resume_all();
+    assert_eq!(manager.count(), 3);
+    let children = manager.state(id).unwrap().clone();
+    assert_eq!(children, vec![1, 2]);
+    assert_eq!(manager.run(deferred!(vec![], [])), 3);
+    manager.resume_all();
+    assert!(!manager.has(id));
+    assert!(!manager.has(children[0]));
+    let d = deferred!(
+        vec![],
+        [|c: Context<Vec<usize>>| {
+            assert!(c.spawner().is_none());
+            state!(c.state())
+        }]
+    );
+    assert!(d.resume().is_some());
+    let id = manager.run(deferred!(
+        vec![],
+        [|c: Context<Vec<usize>>| {
+            let spawner = c.spawner().unwrap();
+            let grandchild = deferred!(
+                vec![],
+                [|c: Context<Vec<usize>>| {
+                    let spawner = c.spawner().unwrap();
+                    state!(vec![spawner.run(deferred!(vec![], []))])
+                }]
+            );
+            state!(vec![spawner.run(grandchild)])
+        }]
+    ));
+    let child = manager.consume(id).unwrap()[0];
+    assert!(manager.has(child));
+    manager.resume_all();
+    assert_eq!(manager.count(), 1);
+    manager.resume_all();
+    assert_eq!(manager.count(), 0);
+}
+
+#[test]
+fn test_env() {
+    type Envs = Vec<Env>;
+
+    fn record(c: Context<Envs>) -> Context<Envs> {
+        let env = *c.env();
+        let mut envs = c.state();
+        envs.push(env);
+        state!(envs)
+    }
+
+    fn sub(c: Context<Envs>) -> Context<Envs> {
+        deferred!(c.state(), [record, sub2, record]).into()
+    }
+
+    fn sub2(c: Context<Envs>) -> Context<Envs> {
+        deferred!(c.state(), [record]).into()
+    }
+
+    let envs = deferred!(vec![], [record, sub, record]).consume();
+    let summary = envs
+        .iter()
+        .map(|env| (env.id, env.time, env.steps, env.depth))
+        .collect::<Vec<_>>();
+    assert_eq!(
+        summary,
+        vec![
+            (None, 0, 0, 0),
+            (None, 0, 2, 1),
+            (None, 0, 4, 2),
+            (None, 0, 5, 1),
+            (None, 0, 6, 0),
+        ]
+    );
+
+    let mut manager = DeferredManager::new();
+    manager.advance_time(3);
+    manager.run(deferred!(vec![], []));
+    let id = manager.run(deferred!(vec![], [record, record, record]));
+    manager.resume(id);
+    manager.advance_time(4);
+    manager.resume_all();
+    manager.advance_time(1);
+    let envs = manager.consume(id).unwrap();
+    let summary = envs
+        .iter()
+        .map(|env| (env.id, env.started, env.elapsed(), env.steps))
+        .collect::<Vec<_>>();
+    assert_eq!(
+        summary,
+        vec![(Some(1), 3, 0, 0), (Some(1), 3, 4, 1), (Some(1), 3, 5, 2)]
+    );
+}
+
+#[test]
+fn test_resources() {
+    struct Counter(usize);
+
+    fn count(c: Context<usize>) -> Context<usize> {
+        let v = c
+            .resource_mut(|counter: &mut Counter| {
+                counter.0 += 1;
+                counter.0
+            })
+            .unwrap();
+        if let Some(spawner) = c.spawner().cloned() {
+            if v == 1 {
+                spawner.run(deferred!(0, [count]));
+            }
+        }
+        state!(c.state() + v)
+    }
+
+    let mut manager = DeferredManager::new();
+    assert!(!manager.resources().has::<Counter>());
+    manager.resources().insert(Counter(0));
+    let a = manager.run(deferred!(0, [count, count]));
+    let b = manager.run(deferred!(0, [count]));
+    manager.resume_all();
+    assert!(manager.state(a).is_some());
+    assert!(!manager.has(b));
+    assert_eq!(
+        manager.resources().with(|counter: &Counter| counter.0),
+        Some(2)
+    );
+    assert!(manager.resume(a));
+    assert!(!manager.has(a));
+    manager.resume_all();
+    assert_eq!(
+        manager.resources().with(|counter: &Counter| counter.0),
+        Some(4)
+    );
+    assert_eq!(manager.count(), 0);
+    assert!(deferred!(
+        0,
+        [|c: Context<usize>| {
+            assert!(c.resources().is_none());
+            assert!(c.resource(|_: &Counter| ()).is_none());
+            state!(c.state())
+        }]
+    )
+    .resume()
+    .is_some());
+    assert_eq!(manager.resources().take::<Counter>().unwrap().0, 4);
+    assert!(manager.resources().is_empty());
+}
+
+#[test]
+fn test_resources_borrowed_together() {
+    struct Counter(usize);
+    struct Log(Vec<usize>);
+
+    let mut manager = DeferredManager::new();
+    manager.resources().insert(Counter(1));
+    manager.resources().insert(Log(vec![]));
+    manager.run(deferred!(
+        0,
+        [|c: Context<usize>| {
+            let pushed = c.resource_mut(|counter: &mut Counter| {
+                counter.0 += 1;
+                c.resource_mut(|log: &mut Log| log.0.push(counter.0))
+            });
+            assert_eq!(pushed, Some(Some(())));
+            state!(c.state())
+        }]
+    ));
+    manager.resume_all();
+    let resources = manager.resources();
+    assert_eq!(resources.len(), 2);
+    resources.with(|counter: &Counter| {
+        assert_eq!(resources.insert(3u8), None);
+        assert_eq!(resources.take::<Log>().unwrap().0, vec![2]);
+        assert_eq!(counter.0, 2);
+    });
+    assert_eq!(resources.take::<u8>(), Some(3));
+    assert_eq!(resources.take::<Counter>().unwrap().0, 2);
+}
+
+#[test]
+fn test_scopes() {
+    use std::sync::{Arc, Mutex};
+
+    type Log = Arc<Mutex<Vec<i32>>>;
+
+    fn unit(log: &Log, v: i32, parts: usize) -> Deferred<i32> {
+        let log = log.clone();
+        let mut builder = DeferredBuilder::new(v);
+        for _ in 0..parts {
+            builder = builder.then(|c| state!(c.state() + 1));
+        }
+        builder
+            .build()
+            .on_cancel(move |v| log.lock().unwrap().push(v))
+    }
+
+    let log = Log::default();
+    let mut manager = DeferredManager::new();
+    let l = log.clone();
+    let parent = manager.run(
+        deferred!(
+            0,
+            [|c: Context<i32>| {
+                let child = deferred!(10, [|c| state!(c.state() + 1), |c| state!(c.state() + 1)]);
+                assert!(c.run_child(child).is_some());
+                state!(c.state() + 1)
+            }]
+        )
+        .on_cancel(move |v| l.lock().unwrap().push(v)),
+    );
+    manager.resume_all();
+    assert!(manager.is_joining(parent));
+    assert_eq!(manager.count(), 2);
+    let child = manager.children(parent)[0];
+    assert_eq!(manager.parent(child), Some(parent));
+    manager.resume_all();
+    assert!(manager.has(parent));
+    assert_eq!(manager.state(child), Some(&11));
+    manager.resume_all();
+    assert!(!manager.has(child));
+    assert!(!manager.has(parent));
+    assert!(log.lock().unwrap().is_empty());
+
+    let parent = manager.run(unit(&log, 0, 1));
+    let child = manager.run_child(parent, unit(&log, 10, 5)).unwrap();
+    assert!(manager.detach(child));
+    assert!(!manager.detach(child));
+    manager.resume_all();
+    assert!(!manager.has(parent));
+    assert!(manager.has(child));
+    assert!(manager.cancel(child));
+    assert_eq!(*log.lock().unwrap(), vec![11]);
+    assert_eq!(manager.run_child(parent, unit(&log, 0, 1)), None);
+
+    log.lock().unwrap().clear();
+    manager.set_default_limits(Some(Limits {
+        max_steps: Some(2),
+        ..Default::default()
+    }));
+    let parent = manager.run(unit(&log, 0, 5));
+    let child = manager.run_child(parent, unit(&log, 10, 5)).unwrap();
+    let grandchild = manager.run_child(child, unit(&log, 20, 5)).unwrap();
+    manager.resume(parent);
+    manager.resume(parent);
+    manager.resume(parent);
+    assert_eq!(
+        manager.failure(parent),
+        Some(&Failure::Limit(LimitExceeded::Steps(2)))
+    );
+    assert!(!manager.has(child));
+    assert!(!manager.has(grandchild));
+    log.lock().unwrap().sort();
+    assert_eq!(*log.lock().unwrap(), vec![2, 10, 20]);
+    manager.set_default_limits(None);
+
+    log.lock().unwrap().clear();
+    let parent = manager.run(unit(&log, 0, 2));
+    let child = manager.run_child(parent, unit(&log, 10, 2)).unwrap();
+    let other = manager.run(unit(&log, 30, 2));
+    assert_eq!(manager.consume(parent), Some(2));
+    assert!(!manager.has(child));
+    manager.run_child(other, unit(&log, 40, 2)).unwrap();
+    manager.retain(|id, _| id != other);
+    assert_eq!(manager.count(), 0);
+    log.lock().unwrap().sort();
+    assert_eq!(*log.lock().unwrap(), vec![30, 40]);
+
+    for consume in [false, true] {
+        let root = manager.run(deferred!(0, [|c| state!(c.state() + 1)]));
+        let mut leaf = root;
+        for _ in 0..100_000 {
+            leaf = manager
+                .run_child(leaf, deferred!(0, [|c| state!(c.state() + 1)]))
+                .unwrap();
+        }
+        if consume {
+            assert_eq!(manager.consume(root), Some(1));
+        } else {
+            assert!(manager.cancel(root));
+        }
+        assert_eq!(manager.count(), 0);
+    }
+
+    #[cfg(feature = "std")]
+    {
+        manager.set_isolate_panics(true);
+        let parent = manager.run(unit(&log, 0, 2));
+        let child = manager
+            .run_child(parent, unit(&log, 10, 2).on_cancel(|_| panic!("cleanup")))
+            .unwrap();
+        assert!(manager.cancel(parent));
+        assert_eq!(
+            manager.failure(child),
+            Some(&Failure::Panic("cleanup".to_owned()))
+        );
+        assert_eq!(manager.failure(parent), None);
+        assert_eq!(manager.count(), 0);
+    }
+}
+
+#[test]
+fn test_join_handle() {
+    fn unit(v: i32) -> Deferred<i32> {
+        deferred!(v, [|c| state!(c.state() + 1), |c| state!(c.state() + 1)])
+    }
+
+    let mut manager = DeferredManager::new();
+    let completed = manager.spawn(unit(0));
+    let waiters = vec![completed.clone(), completed.clone()];
+    let cancelled = manager.spawn(unit(10));
+    let detached = manager.spawn(unit(20));
+    let detached_id = detached.id();
+    detached.detach();
+    let child = manager
+        .run_child(completed.id(), unit(0).then(unit(0)))
+        .unwrap();
+    assert!(manager.cancel(cancelled.id()));
+    assert_eq!(cancelled.outcome().as_deref(), Some(&Outcome::Cancelled));
+    manager.resume_all();
+    assert_eq!(manager.state(detached_id), Some(&21));
+    manager.resume_all();
+    assert!(manager.is_joining(completed.id()));
+    assert!(!completed.is_finished());
+    assert!(manager.detach(child));
+    assert!(completed.is_finished());
+    for waiter in &waiters {
+        assert_eq!(waiter.state().as_deref(), Some(&2));
+    }
+    assert_eq!(completed.take(), Some(2));
+    assert_eq!(completed.take(), None);
+    assert_eq!(waiters[0].outcome().as_deref(), Some(&Outcome::Consumed));
+    assert!(manager.has(child));
+
+    manager.set_default_limits(Some(Limits {
+        max_steps: Some(1),
+        ..Default::default()
+    }));
+    let failed = manager.spawn(unit(0));
+    manager.set_default_limits(None);
+    let consumed = manager.spawn(unit(0));
+    let dropped = manager.spawn(unit(0)).id();
+    manager.resume_all();
+    assert!(!manager.has(dropped));
+    manager.resume_all();
+    assert_eq!(
+        failed.outcome().as_deref(),
+        Some(&Outcome::Failed(Failure::Limit(LimitExceeded::Steps(1))))
+    );
+    assert_eq!(consumed.state().as_deref(), Some(&2));
+
+    let dropped = manager.spawn(unit(0)).id();
+    let other = manager.run(unit(0));
+    assert!(manager.resume(other));
+    assert!(!manager.has(dropped));
+    let dropped = manager.spawn(unit(0)).id();
+    manager.advance_time(1);
+    assert!(!manager.has(dropped));
+    manager.consume(other);
+
+    let a = manager.spawn(unit(0));
+    let b = manager.spawn(deferred!(0, []));
+    assert_eq!(manager.consume(a.id()), Some(2));
+    assert_eq!(a.outcome().as_deref(), Some(&Outcome::Consumed));
+    manager.consume_all();
+    assert_eq!(b.outcome().as_deref(), Some(&Outcome::Cancelled));
+}
@@ -1,4 +1,175 @@
-use std::any::Any;
+use alloc::boxed::Box;
+use core::{
+    any::{type_name, Any, TypeId},
+    fmt,
+};
+
+/// Error produced when trying to access value as type other than the one it stores.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ValueError {
+    /// Name of requested type.
+    pub expected: &'static str,
+    /// Name of stored type.
+    pub found: &'static str,
+}
+
+impl fmt::Display for ValueError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "Expected value of type `{}` but it stores `{}`",
+            self.expected, self.found
+        )
+    }
+}
+
+#[cfg(feature = "std")]
+impl std::error::Error for ValueError {}
+
+macro_rules! impl_value {
+    ($name:ident, $any:ty, $($bounds:tt)*) => {
+        impl $name {
+            /// Returns new value. Name of its type is not known so diagnostics will report it as
+            /// trait object - use `from_box()` to keep it.
+            ///
+            /// # Arguments
+            /// * `value` - boxed value of any type.
+            pub fn new(value: Box<$any>) -> Self {
+                Self {
+                    inner: value,
+                    type_name: type_name::<$any>(),
+                }
+            }
+
+            /// Returns new value that remembers name of its type.
+            ///
+            /// # Arguments
+            /// * `value` - boxed value.
+            pub fn from_box<T: $($bounds)*>(value: Box<T>) -> Self {
+                Self {
+                    inner: value,
+                    type_name: type_name::<T>(),
+                }
+            }
+
+            /// Gets name of stored value type.
+            #[inline]
+            pub fn type_name(&self) -> &'static str {
+                self.type_name
+            }
+
+            /// Gets type id of stored value.
+            #[inline]
+            pub fn type_id(&self) -> TypeId {
+                (*self.inner).type_id()
+            }
+
+            /// Tells if value is type of given type.
+            #[inline]
+            pub fn is<T: 'static>(&self) -> bool {
+                self.inner.is::<T>()
+            }
+
+            /// Gets reference to value of given type or `None` if its not of that type.
+            #[inline]
+            pub fn get<T: 'static>(&self) -> Option<&T> {
+                self.inner.downcast_ref::<T>()
+            }
+
+            /// Gets mutable reference to value of given type or `None` if its not of that type.
+            #[inline]
+            pub fn get_mut<T: 'static>(&mut self) -> Option<&mut T> {
+                self.inner.downcast_mut::<T>()
+            }
+
+            /// Gets cloned value of given type or `None` if its not of that type.
+            #[inline]
+            #[allow(clippy::wrong_self_convention)]
+            pub fn into_cloned<T: 'static + Clone>(&self) -> Option<T> {
+                self.get::<T>().cloned()
+            }
+
+            /// Takes value of given type out of the box or returns itself back if its not of that
+            /// type.
+            pub fn take<T: 'static>(self) -> Result<T, Self> {
+                let type_name = self.type_name;
+                self.inner
+                    .downcast::<T>()
+                    .map(|value| *value)
+                    .map_err(|inner| Self { inner, type_name })
+            }
+
+            /// Gets reference to value of given type or error if its not of that type.
+            pub fn try_unwrap<T: 'static>(&self) -> Result<&T, ValueError> {
+                let error = self.error::<T>();
+                self.get::<T>().ok_or(error)
+            }
+
+            /// Gets mutable reference to value of given type or error if its not of that type.
+            pub fn try_unwrap_mut<T: 'static>(&mut self) -> Result<&mut T, ValueError> {
+                let error = self.error::<T>();
+                self.get_mut::<T>().ok_or(error)
+            }
+
+            /// Consumes value of given type and returns it or error if its not of that type.
+            pub fn try_consume<T: 'static>(self) -> Result<T, ValueError> {
+                let error = self.error::<T>();
+                self.take::<T>().map_err(|_| error)
+            }
+
+            /// Gets reference to value of given type or panics if its not of that type.
+            ///
+            /// # Panics
+            /// * when trying to use target type other than that of inner value.
+            #[inline]
+            pub fn unwrap<T: 'static>(&self) -> &T {
+                match self.try_unwrap::<T>() {
+                    Ok(value) => value,
+                    Err(error) => panic!("{}", error),
+                }
+            }
+
+            /// Gets mutable reference to value of given type or panics if its not of that type.
+            ///
+            /// # Panics
+            /// * when trying to use target type other than that of inner value.
+            #[inline]
+            pub fn unwrap_mut<T: 'static>(&mut self) -> &mut T {
+                match self.try_unwrap_mut::<T>() {
+                    Ok(value) => value,
+                    Err(error) => panic!("{}", error),
+                }
+            }
+
+            /// Consumes value of given type and returns it or panics if its not of that type.
+            ///
+            /// # Panics
+            /// * when trying to use target type other than that of inner value.
+            #[inline]
+            pub fn consume<T: 'static>(self) -> T {
+                match self.try_consume::<T>() {
+                    Ok(value) => value,
+                    Err(error) => panic!("{}", error),
+                }
+            }
+
+            fn error<T: 'static>(&self) -> ValueError {
+                ValueError {
+                    expected: type_name::<T>(),
+                    found: self.type_name,
+                }
+            }
+        }
+
+        impl fmt::Debug for $name {
+            fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+                f.debug_struct(stringify!($name))
+                    .field("type_name", &self.type_name)
+                    .finish()
+            }
+        }
+    };
+}
 
 /// Wrapper over value of non-specified type.
 ///
@@ -7,84 +178,55 @@ use std::any::Any;
 /// # #[macro_use] extern crate deferred;
 /// # use deferred::Value;
 /// # fn main() {
-/// let v = Value::new(Box::new(42));
+/// let v = Value::from_box(Box::new(42));
 /// assert_eq!(v.is::<i32>(), true);
 /// assert_eq!(v.is::<f32>(), false);
+/// assert_eq!(v.type_name(), "i32");
 /// assert_eq!(v.get::<i32>(), Some(&42));
 /// assert_eq!(v.into_cloned::<i32>(), Some(42));
 /// assert_eq!(v.into_cloned::<f32>(), None);
 /// assert_eq!(v.unwrap::<i32>(), &42);
+/// assert_eq!(
+///     v.try_unwrap::<f32>().unwrap_err().to_string(),
+///     "Expected value of type `f32` but it stores `i32`",
+/// );
+/// let v = v.take::<f32>().unwrap_err();
 /// assert_eq!(v.consume::<i32>(), 42);
 /// # }
 /// ```
 pub struct Value {
-    inner: Box<Any>,
+    inner: Box<dyn Any>,
+    type_name: &'static str,
 }
 
-impl Value {
-    /// Returns new value.
-    ///
-    /// # Arguments
-    /// * `value` - boxed value of any type.
-    pub fn new(value: Box<Any>) -> Self {
-        Self { inner: value }
-    }
-
-    /// Tells if value is type of given type.
-    #[inline]
-    pub fn is<T: 'static>(&self) -> bool {
-        self.inner.is::<T>()
-    }
-
-    /// Gets reference to value of given type or `None` if its not of that type.
-    #[inline]
-    pub fn get<T: 'static>(&self) -> Option<&T> {
-        self.inner.downcast_ref::<T>()
-    }
-
-    /// Gets mutable reference to value of given type or `None` if its not of that type.
-    #[inline]
-    pub fn get_mut<T: 'static>(&mut self) -> Option<&mut T> {
-        self.inner.downcast_mut::<T>()
-    }
-
-    /// Gets cloned value of given type or `None` if its not of that type.
-    #[inline]
-    #[allow(clippy::wrong_self_convention)]
-    pub fn into_cloned<T: 'static>(&self) -> Option<T>
-    where
-        T: Clone,
-    {
-        self.get::<T>().cloned()
-    }
-
-    /// Gets reference to value of given type or panics if its not of that type.
-    ///
-    /// # Panics
-    /// * when trying to use target type other than that of inner value.
-    #[inline]
-    pub fn unwrap<T: 'static>(&self) -> &T {
-        self.get::<T>().unwrap()
-    }
+impl_value!(Value, dyn Any, 'static);
 
-    /// Gets mutable reference to value of given type or panics if its not of that type.
-    ///
-    /// # Panics
-    /// * when trying to use target type other than that of inner value.
-    #[inline]
-    pub fn unwrap_mut<T: 'static>(&mut self) -> &mut T {
-        self.get_mut::<T>().unwrap()
+impl From<SendValue> for Value {
+    fn from(value: SendValue) -> Self {
+        Self {
+            inner: value.inner,
+            type_name: value.type_name,
+        }
     }
+}
 
-    /// Consumes value of given type and returns it or panics if its not of that type.
-    ///
-    /// # Panics
-    /// * when trying to use target type other than that of inner value.
-    #[inline]
-    pub fn consume<T: 'static>(self) -> T
-    where
-        T: Clone,
-    {
-        self.into_cloned::<T>().unwrap()
-    }
+/// Wrapper over value of non-specified type that can be sent to another thread.
+///
+/// # Example
+/// ```
+/// # #[macro_use] extern crate deferred;
+/// # use deferred::{SendValue, Value};
+/// # fn main() {
+/// let v = send_value!(String::from("hello"));
+/// let v = std::thread::spawn(move || v).join().unwrap();
+/// assert_eq!(v.get::<String>().map(String::as_str), Some("hello"));
+/// let v = Value::from(v);
+/// assert_eq!(v.consume::<String>(), "hello");
+/// # }
+/// ```
+pub struct SendValue {
+    inner: Box<dyn Any + Send>,
+    type_name: &'static str,
 }
+
+impl_value!(SendValue, dyn Any + Send, 'static + Send);
@@ -0,0 +1,130 @@
+use ::deferred::*;
+use std::alloc::{GlobalAlloc, Layout, System};
+use std::cell::Cell;
+
+struct CountingAllocator;
+
+thread_local! {
+    static ALLOCATIONS: Cell<usize> = const { Cell::new(0) };
+}
+
+fn allocations() -> usize {
+    ALLOCATIONS.with(Cell::get)
+}
+
+unsafe impl GlobalAlloc for CountingAllocator {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        let _ = ALLOCATIONS.try_with(|count| count.set(count.get() + 1));
+        System.alloc(layout)
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        System.dealloc(ptr, layout)
+    }
+}
+
+#[global_allocator]
+static ALLOCATOR: CountingAllocator = CountingAllocator;
+
+#[test]
+fn test_array_deferred_does_not_allocate() {
+    fn foo(v: i32) -> ArrayDeferred<i32, 3> {
+        ArrayDeferred::new(
+            v,
+            &[
+                |c| state!(c.state() + 1),
+                |c| state!(c.state() * 2),
+                |c| state!(c.state() + 3),
+            ],
+        )
+    }
+
+    let before = allocations();
+    let mut d = foo(1);
+    while d.can_resume() {
+        d = d.resume().unwrap();
+    }
+    let result = foo(*d.state().unwrap()).consume();
+    assert_eq!(allocations(), before);
+    assert_eq!(result, 19);
+}
+
+#[test]
+fn test_deferred_resume_does_not_allocate() {
+    fn foo(v: i32) -> Deferred<i32> {
+        deferred!(
+            v,
+            [
+                |c| state!(c.state() + 1),
+                |c| state!(c.state() * 2),
+                |c| state!(c.state() + 3)
+            ]
+        )
+    }
+
+    let mut d = foo(1);
+    let before = allocations();
+    while d.can_resume() {
+        d = d.resume().unwrap();
+    }
+    assert_eq!(allocations(), before);
+    assert_eq!(d.state(), Some(&7));
+}
+
+#[test]
+fn test_array_deferred_nesting_does_not_allocate() {
+    struct Scale;
+
+    impl StaticSubroutine<i32> for Scale {
+        const PARTS: &'static [Part<i32>] = &[
+            |c| state!(c.state() * 2),
+            |c| Context::from_static::<Offset>(c.state()),
+        ];
+    }
+
+    struct Offset;
+
+    impl StaticSubroutine<i32> for Offset {
+        const PARTS: &'static [Part<i32>] = &[|c| state!(c.state() + 3)];
+    }
+
+    fn foo(v: i32) -> ArrayDeferred<i32, 4> {
+        ArrayDeferred::new(
+            v,
+            &[
+                |c| state!(c.state() + 1),
+                |c| Context::from_static::<Scale>(c.state()),
+                |c| state!(c.state() * 10),
+            ],
+        )
+    }
+
+    let before = allocations();
+    let mut d = foo(1);
+    while d.can_resume() {
+        d = d.resume().unwrap();
+    }
+    let result = foo(*d.state().unwrap()).consume();
+    assert_eq!(allocations(), before);
+    assert_eq!(result, 1450);
+}
+
+#[test]
+fn test_any_deferred_manager_resume_does_not_allocate() {
+    let mut manager = AnyDeferredManager::new();
+    let handle = manager.run(deferred!(
+        1,
+        [
+            |c| state!(c.state() + 1),
+            |c| state!(c.state() * 2),
+            |c| state!(c.state() + 3),
+            |c| state!(c.state() * 10)
+        ]
+    ));
+    let before = allocations();
+    assert!(manager.resume(handle.id()));
+    assert!(manager.resume(handle.id()));
+    assert!(manager.resume(handle.id()));
+    assert_eq!(allocations(), before);
+    assert_eq!(manager.state(handle), Some(&7));
+}
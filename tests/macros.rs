@@ -0,0 +1,108 @@
+use ::deferred::{deferred, state, subdeferred, try_state, value, Deferred, Value};
+
+#[test]
+fn test_deferred() {
+    fn foo(v: i32) -> Deferred<i32> {
+        deferred!(v, [|c| state!(c.state() + 1), |c| state!(c.state() + 2),])
+    }
+
+    assert_eq!(foo(1).consume(), 4);
+    assert_eq!(deferred!(1).consume(), 1);
+}
+
+#[test]
+fn test_named() {
+    fn foo(v: i32) -> Deferred<i32> {
+        deferred!(
+            v,
+            [
+                "first" => |c| state!(c.state() + 1),
+                |c| state!(c.state() + 2),
+                "third" => [|c| state!(c.state() * 2)]
+            ]
+        )
+    }
+
+    let d = foo(1);
+    assert_eq!(d.next_part_name(), Some("first"));
+    let d = d.resume().unwrap();
+    assert_eq!(d.next_part_name(), None);
+    let d = d.resume().unwrap();
+    assert_eq!(d.next_part_name(), Some("third"));
+    assert_eq!(d.consume(), 8);
+}
+
+#[test]
+fn test_closure() {
+    fn foo(v: i32, name: String) -> Deferred<String> {
+        let suffix = "!".to_owned();
+        deferred!(
+            v.to_string(),
+            [
+                move |c| state!(format!("{} {}", name, c.state())),
+                |c| state!(c.state().to_uppercase()),
+                "suffix" => move |c| state!(c.state() + suffix.as_str())
+            ]
+        )
+    }
+
+    assert_eq!(foo(42, "answer".to_owned()).consume(), "ANSWER 42!");
+}
+
+#[test]
+fn test_subdeferred() {
+    fn foo(v: i32, n: i32) -> Deferred<i32> {
+        deferred!(
+            v,
+            [
+                |c| subdeferred!(
+                    c.state(),
+                    [|c| state!(c.state() + 1), [|c| state!(c.state() * 10)]]
+                ),
+                move |c| subdeferred!(c.state(), [move |c| state!(c.state() - n)]),
+                |c| subdeferred!(c.state())
+            ]
+        )
+    }
+
+    let d = foo(1, 5);
+    let d = d.resume().unwrap();
+    assert_eq!(d.state(), Some(&2));
+    let d = d.resume().unwrap();
+    assert_eq!(d.state(), Some(&20));
+    let d = d.resume().unwrap();
+    assert_eq!(d.state(), Some(&15));
+    let d = d.resume().unwrap();
+    assert!(!d.can_resume());
+    assert_eq!(d.state(), Some(&15));
+}
+
+#[test]
+fn test_try_state() {
+    fn foo(v: u8) -> Deferred<Result<u8, String>> {
+        deferred!(
+            Ok(v),
+            [
+                |c| try_state!(c.state(), |v| v
+                    .checked_mul(2)
+                    .ok_or_else(|| "overflow".to_owned())),
+                |c| try_state!(c.state(), |v| Ok(v + 1))
+            ]
+        )
+    }
+
+    assert_eq!(foo(10).consume(), Ok(21));
+    assert_eq!(foo(200).consume(), Err("overflow".to_owned()));
+}
+
+#[test]
+fn test_value() {
+    fn foo(v: i32) -> Deferred<Value> {
+        deferred!(
+            value!(v),
+            [|c| state!(value!(c.state().consume::<i32>() + 1))]
+        )
+    }
+
+    assert_eq!(foo(41).consume().consume::<i32>(), 42);
+}